@@ -0,0 +1,3 @@
+-- This file should undo anything in `up.sql`
+ALTER TABLE buffs DROP COLUMN advantage;
+ALTER TABLE buffs DROP COLUMN disadvantage;
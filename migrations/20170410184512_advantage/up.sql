@@ -0,0 +1,3 @@
+-- Your SQL goes here
+ALTER TABLE buffs ADD COLUMN advantage VARCHAR[] NOT NULL DEFAULT '{}';
+ALTER TABLE buffs ADD COLUMN disadvantage VARCHAR[] NOT NULL DEFAULT '{}';
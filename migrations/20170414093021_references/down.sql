@@ -0,0 +1,3 @@
+-- This file should undo anything in `up.sql`
+ALTER TABLE players DROP COLUMN level;
+ALTER TABLE abilities DROP COLUMN to_hit;
@@ -0,0 +1,3 @@
+-- Your SQL goes here
+ALTER TABLE players ADD COLUMN level INT NOT NULL DEFAULT 1;
+ALTER TABLE abilities ADD COLUMN to_hit VARCHAR;
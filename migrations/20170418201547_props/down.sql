@@ -0,0 +1,3 @@
+-- This file should undo anything in `up.sql`
+-- The props table predates this migration (up.sql only creates it where
+-- it's missing), so it's left alone rather than dropped with its data.
@@ -0,0 +1,6 @@
+-- Your SQL goes here
+CREATE TABLE IF NOT EXISTS props (
+       id SERIAL PRIMARY KEY,
+       name VARCHAR NOT NULL,
+       value VARCHAR NOT NULL
+);
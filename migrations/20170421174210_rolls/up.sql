@@ -0,0 +1,13 @@
+-- Your SQL goes here
+CREATE TABLE rolls (
+       id SERIAL PRIMARY KEY,
+       nick VARCHAR NOT NULL,
+       room VARCHAR NOT NULL,
+       player_id INT,
+       spec VARCHAR NOT NULL,
+       result INT NOT NULL,
+       breakdown VARCHAR NOT NULL,
+       seed BIGINT NOT NULL,
+       draw BIGINT NOT NULL,
+       rolled_at TIMESTAMP NOT NULL DEFAULT now()
+);
@@ -0,0 +1,7 @@
+-- Your SQL goes here
+CREATE TABLE macros (
+       id SERIAL PRIMARY KEY,
+       name VARCHAR NOT NULL,
+       spec VARCHAR NOT NULL,
+       player_id INT
+);
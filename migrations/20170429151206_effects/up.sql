@@ -0,0 +1,10 @@
+-- Your SQL goes here
+CREATE TABLE effects (
+       id SERIAL PRIMARY KEY,
+       name VARCHAR NOT NULL,
+       kind VARCHAR NOT NULL,
+       combatant_id INT NOT NULL,
+       source_id INT NOT NULL,
+       rounds_left INT NOT NULL
+);
+ALTER TABLE abilities ADD COLUMN duration INT;
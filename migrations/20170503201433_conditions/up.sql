@@ -0,0 +1,2 @@
+-- Your SQL goes here
+ALTER TABLE combatants ADD COLUMN conditions VARCHAR[] NOT NULL DEFAULT '{}';
@@ -0,0 +1,9 @@
+-- This file should undo anything in `up.sql`
+ALTER TABLE monsters DROP COLUMN damage_vulnerabilities;
+ALTER TABLE monsters DROP COLUMN damage_resistances;
+ALTER TABLE monsters DROP COLUMN damage_immunities;
+ALTER TABLE monsters DROP COLUMN condition_immunities;
+ALTER TABLE players DROP COLUMN damage_vulnerabilities;
+ALTER TABLE players DROP COLUMN damage_resistances;
+ALTER TABLE players DROP COLUMN damage_immunities;
+ALTER TABLE abilities DROP COLUMN damage_type;
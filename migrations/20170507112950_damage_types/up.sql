@@ -0,0 +1,9 @@
+-- Your SQL goes here
+ALTER TABLE monsters ADD COLUMN damage_vulnerabilities VARCHAR[] NOT NULL DEFAULT '{}';
+ALTER TABLE monsters ADD COLUMN damage_resistances VARCHAR[] NOT NULL DEFAULT '{}';
+ALTER TABLE monsters ADD COLUMN damage_immunities VARCHAR[] NOT NULL DEFAULT '{}';
+ALTER TABLE monsters ADD COLUMN condition_immunities VARCHAR[] NOT NULL DEFAULT '{}';
+ALTER TABLE players ADD COLUMN damage_vulnerabilities VARCHAR[] NOT NULL DEFAULT '{}';
+ALTER TABLE players ADD COLUMN damage_resistances VARCHAR[] NOT NULL DEFAULT '{}';
+ALTER TABLE players ADD COLUMN damage_immunities VARCHAR[] NOT NULL DEFAULT '{}';
+ALTER TABLE abilities ADD COLUMN damage_type VARCHAR;
@@ -0,0 +1,8 @@
+-- This file should undo anything in `up.sql`
+ALTER TABLE monsters DROP COLUMN strength_save;
+ALTER TABLE monsters DROP COLUMN dexterity_save;
+ALTER TABLE monsters DROP COLUMN constitution_save;
+ALTER TABLE monsters DROP COLUMN intelligence_save;
+ALTER TABLE monsters DROP COLUMN wisdom_save;
+ALTER TABLE monsters DROP COLUMN charisma_save;
+ALTER TABLE players DROP COLUMN save_proficiencies;
@@ -0,0 +1,8 @@
+-- Your SQL goes here
+ALTER TABLE monsters ADD COLUMN strength_save INT;
+ALTER TABLE monsters ADD COLUMN dexterity_save INT;
+ALTER TABLE monsters ADD COLUMN constitution_save INT;
+ALTER TABLE monsters ADD COLUMN intelligence_save INT;
+ALTER TABLE monsters ADD COLUMN wisdom_save INT;
+ALTER TABLE monsters ADD COLUMN charisma_save INT;
+ALTER TABLE players ADD COLUMN save_proficiencies VARCHAR[] NOT NULL DEFAULT '{}';
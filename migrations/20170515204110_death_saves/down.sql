@@ -0,0 +1,5 @@
+-- This file should undo anything in `up.sql`
+ALTER TABLE combatants DROP COLUMN death_successes;
+ALTER TABLE combatants DROP COLUMN death_failures;
+ALTER TABLE combatants DROP COLUMN stable;
+ALTER TABLE combatants DROP COLUMN dead;
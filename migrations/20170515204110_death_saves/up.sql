@@ -0,0 +1,5 @@
+-- Your SQL goes here
+ALTER TABLE combatants ADD COLUMN death_successes INT NOT NULL DEFAULT 0;
+ALTER TABLE combatants ADD COLUMN death_failures INT NOT NULL DEFAULT 0;
+ALTER TABLE combatants ADD COLUMN stable BOOLEAN NOT NULL DEFAULT false;
+ALTER TABLE combatants ADD COLUMN dead BOOLEAN NOT NULL DEFAULT false;
@@ -0,0 +1,2 @@
+-- Your SQL goes here
+ALTER TABLE players ADD COLUMN xp INT NOT NULL DEFAULT 0;
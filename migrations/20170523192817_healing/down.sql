@@ -0,0 +1,3 @@
+-- This file should undo anything in `up.sql`
+ALTER TABLE combatants DROP COLUMN temp_hp;
+ALTER TABLE abilities DROP COLUMN healing;
@@ -0,0 +1,3 @@
+-- Your SQL goes here
+ALTER TABLE combatants ADD COLUMN temp_hp INT NOT NULL DEFAULT 0;
+ALTER TABLE abilities ADD COLUMN healing BOOLEAN NOT NULL DEFAULT false;
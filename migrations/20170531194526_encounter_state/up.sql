@@ -0,0 +1,8 @@
+-- Your SQL goes here
+CREATE TABLE encounter_state (
+       id SERIAL PRIMARY KEY,
+       cur_combatant INT,
+       cur_round INT NOT NULL DEFAULT 0,
+       room_id INT,
+       started_at TIMESTAMP
+);
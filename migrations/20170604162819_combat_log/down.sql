@@ -0,0 +1,3 @@
+-- This file should undo anything in `up.sql`
+DROP TABLE combat_changes;
+DROP TABLE combat_actions;
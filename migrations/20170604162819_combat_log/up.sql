@@ -0,0 +1,16 @@
+-- Your SQL goes here
+CREATE TABLE combat_actions (
+       id SERIAL PRIMARY KEY,
+       nick VARCHAR NOT NULL,
+       command VARCHAR NOT NULL,
+       logged_at TIMESTAMP NOT NULL DEFAULT now()
+);
+CREATE TABLE combat_changes (
+       id SERIAL PRIMARY KEY,
+       action_id INT NOT NULL,
+       kind VARCHAR NOT NULL,
+       target_id INT,
+       field VARCHAR NOT NULL,
+       old_value VARCHAR NOT NULL,
+       new_value VARCHAR NOT NULL
+);
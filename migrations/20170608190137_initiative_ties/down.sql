@@ -0,0 +1,3 @@
+-- This file should undo anything in `up.sql`
+ALTER TABLE combatants DROP COLUMN init_dex;
+ALTER TABLE combatants DROP COLUMN init_tiebreak;
@@ -0,0 +1,3 @@
+-- Your SQL goes here
+ALTER TABLE combatants ADD COLUMN init_dex INT NOT NULL DEFAULT 10;
+ALTER TABLE combatants ADD COLUMN init_tiebreak INT NOT NULL DEFAULT 0;
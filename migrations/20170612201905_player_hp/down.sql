@@ -0,0 +1,3 @@
+-- This file should undo anything in `up.sql`
+ALTER TABLE players DROP COLUMN cur_hp;
+ALTER TABLE players DROP COLUMN temp_hp;
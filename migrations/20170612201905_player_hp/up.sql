@@ -0,0 +1,5 @@
+-- Your SQL goes here
+ALTER TABLE players ADD COLUMN cur_hp INT;
+UPDATE players SET cur_hp = hit_points;
+ALTER TABLE players ALTER COLUMN cur_hp SET NOT NULL;
+ALTER TABLE players ADD COLUMN temp_hp INT NOT NULL DEFAULT 0;
@@ -0,0 +1,4 @@
+-- This file should undo anything in `up.sql`
+ALTER TABLE abilities DROP COLUMN save_ability;
+ALTER TABLE abilities DROP COLUMN save_dc;
+ALTER TABLE abilities DROP COLUMN half_on_save;
@@ -0,0 +1,4 @@
+-- Your SQL goes here
+ALTER TABLE abilities ADD COLUMN save_ability VARCHAR;
+ALTER TABLE abilities ADD COLUMN save_dc INT;
+ALTER TABLE abilities ADD COLUMN half_on_save BOOLEAN NOT NULL DEFAULT false;
@@ -0,0 +1,2 @@
+-- Your SQL goes here
+ALTER TABLE monsters ADD COLUMN hit_dice VARCHAR;
@@ -0,0 +1,5 @@
+-- This file should undo anything in `up.sql`
+ALTER TABLE players DROP COLUMN death_successes;
+ALTER TABLE players DROP COLUMN death_failures;
+ALTER TABLE players DROP COLUMN stable;
+ALTER TABLE players DROP COLUMN dead;
@@ -0,0 +1,6 @@
+-- Your SQL goes here
+ALTER TABLE players ADD COLUMN death_successes INT NOT NULL DEFAULT 0;
+ALTER TABLE players ADD COLUMN death_failures INT NOT NULL DEFAULT 0;
+ALTER TABLE players ADD COLUMN stable BOOLEAN NOT NULL DEFAULT false;
+ALTER TABLE players ADD COLUMN dead BOOLEAN NOT NULL DEFAULT false;
+UPDATE players SET stable = true WHERE cur_hp <= 0;
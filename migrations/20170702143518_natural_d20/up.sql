@@ -0,0 +1,3 @@
+-- Your SQL goes here
+ALTER TABLE rolls ADD COLUMN natural_d20 INT;
+UPDATE rolls SET natural_d20 = result WHERE spec = '1d20';
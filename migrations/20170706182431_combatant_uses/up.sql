@@ -0,0 +1,8 @@
+-- Your SQL goes here
+CREATE TABLE combatant_uses (
+       id SERIAL PRIMARY KEY,
+       combatant_id INT NOT NULL,
+       ability_id INT NOT NULL,
+       uses_left INT NOT NULL,
+       UNIQUE (combatant_id, ability_id)
+);
@@ -0,0 +1,101 @@
+//! Working out what a combat command changed, so it can be logged and undone.
+//!
+//! Before a logged command runs, we take a `Snapshot` of everything it could
+//! change; afterwards, `diff` turns the two snapshots into a list of field
+//! changes with their old and new values.
+use models::{Combatant, Effect};
+
+/// Commands that change the state of combat, and so get logged.
+const LOGGED: &[&str] = &["atk", "attack", "patk", "catk", "cturn", "use", "puse", "cuse",
+                          "hp", "hp=", "heal", "pheal", "temphp", "stabilize", "stabilise",
+                          "nextturn", "init=", "init", "atk=", "set_current_combatant"];
+/// Fields of a combatant that get logged.
+pub const FIELDS: &[&str] = &["cur_hp", "temp_hp", "initiative", "init_dex", "init_tiebreak",
+                              "attack", "attack_bonus", "conditions", "death_successes", "death_failures",
+                              "stable", "dead"];
+
+/// Whether a command should be logged.
+pub fn is_logged(args: &[&str]) -> bool {
+    match args {
+        &["condition", "add", ..] | &["condition", "remove", ..] => true,
+        _ => args.get(0).map(|a| LOGGED.contains(a)).unwrap_or(false)
+    }
+}
+/// Everything a combat command might change.
+pub struct Snapshot {
+    pub combatants: Vec<Combatant>,
+    pub effects: Vec<Effect>,
+    /// (id, uses left) of every ability with limited uses.
+    pub ability_uses: Vec<(i32, i32)>,
+    /// (id, uses left) of every combatant's uses of its monster's abilities.
+    pub combatant_uses: Vec<(i32, i32)>,
+    pub cur_combatant: Option<i32>,
+    pub cur_round: i32
+}
+/// One thing that changed: a field on a combatant, ability or effect, or on the
+/// encounter itself (in which case `target_id` is `None`).
+#[derive(Debug, PartialEq)]
+pub struct Change {
+    pub kind: &'static str,
+    pub target_id: Option<i32>,
+    pub field: &'static str,
+    pub old_value: String,
+    pub new_value: String
+}
+/// Gets a combatant field (one of `FIELDS`) as a string.
+pub fn field(c: &Combatant, name: &str) -> String {
+    match name {
+        "cur_hp" => c.cur_hp.to_string(),
+        "temp_hp" => c.temp_hp.to_string(),
+        "initiative" => c.initiative.to_string(),
+        "init_dex" => c.init_dex.to_string(),
+        "init_tiebreak" => c.init_tiebreak.to_string(),
+        "attack" => c.attack.clone(),
+        "attack_bonus" => c.attack_bonus.to_string(),
+        "conditions" => c.conditions.join(","),
+        "death_successes" => c.death_successes.to_string(),
+        "death_failures" => c.death_failures.to_string(),
+        "stable" => c.stable.to_string(),
+        "dead" => c.dead.to_string(),
+        _ => String::new()
+    }
+}
+fn change(kind: &'static str, target_id: Option<i32>, field: &'static str, old: String, new: String) -> Option<Change> {
+    if old == new {
+        return None;
+    }
+    Some(Change { kind, target_id, field, old_value: old, new_value: new })
+}
+/// Lists what changed between two snapshots.
+///
+/// Things that only exist in one of the snapshots (like combatants that were
+/// added, or effects that wore off) aren't included.
+pub fn diff(before: &Snapshot, after: &Snapshot) -> Vec<Change> {
+    let mut ret = vec![];
+    for a in after.combatants.iter() {
+        if let Some(b) = before.combatants.iter().find(|b| b.id == a.id) {
+            for &f in FIELDS {
+                ret.extend(change("combatant", Some(a.id), f, field(b, f), field(a, f)));
+            }
+        }
+    }
+    for a in after.effects.iter() {
+        if let Some(b) = before.effects.iter().find(|b| b.id == a.id) {
+            ret.extend(change("effect", Some(a.id), "rounds_left", b.rounds_left.to_string(), a.rounds_left.to_string()));
+        }
+    }
+    for &(id, uses) in after.ability_uses.iter() {
+        if let Some(&(_, old)) = before.ability_uses.iter().find(|&&(bid, _)| bid == id) {
+            ret.extend(change("ability", Some(id), "uses_left", old.to_string(), uses.to_string()));
+        }
+    }
+    for &(id, uses) in after.combatant_uses.iter() {
+        if let Some(&(_, old)) = before.combatant_uses.iter().find(|&&(bid, _)| bid == id) {
+            ret.extend(change("combatant_use", Some(id), "uses_left", old.to_string(), uses.to_string()));
+        }
+    }
+    let show = |c: Option<i32>| c.map(|x| x.to_string()).unwrap_or("none".into());
+    ret.extend(change("encounter", None, "cur_combatant", show(before.cur_combatant), show(after.cur_combatant)));
+    ret.extend(change("encounter", None, "cur_round", before.cur_round.to_string(), after.cur_round.to_string()));
+    ret
+}
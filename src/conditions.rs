@@ -0,0 +1,204 @@
+//! The conditions from the SRD, and what they do to rolls.
+//!
+//! Combat doesn't track distance, so attacks are assumed to be made from
+//! within 5 feet (which matters for prone, paralyzed and unconscious targets).
+use super::RollMode;
+use RollMode::*;
+
+pub struct Condition {
+    pub name: &'static str,
+    /// Short summary, for `condition/[name]`.
+    pub descrip: &'static str,
+    /// How the creature's own attack rolls are affected.
+    pub attacking: Option<RollMode>,
+    /// How attack rolls against the creature are affected.
+    pub attacked: Option<RollMode>,
+    /// How the creature's ability checks are affected.
+    pub checks: Option<RollMode>,
+    /// Whether the creature loses its turns.
+    pub incapacitates: bool,
+    /// Whether any hit against the creature is a critical hit.
+    pub crit_against: bool,
+    /// Whether the creature automatically fails Strength and Dexterity saves.
+    pub fails_saves: bool,
+    /// How the creature's Dexterity saves are affected.
+    pub dex_saves: Option<RollMode>
+}
+
+pub const CONDITIONS: &[Condition] = &[
+    Condition {
+        name: "blinded",
+        descrip: "Can't see. Attacks against it have advantage; its attacks have disadvantage.",
+        attacking: Some(Disadvantage), attacked: Some(Advantage), checks: None,
+        incapacitates: false, crit_against: false,
+        fails_saves: false, dex_saves: None
+    },
+    Condition {
+        name: "charmed",
+        descrip: "Can't attack the charmer, who has advantage on social checks against it.",
+        attacking: None, attacked: None, checks: None,
+        incapacitates: false, crit_against: false,
+        fails_saves: false, dex_saves: None
+    },
+    Condition {
+        name: "deafened",
+        descrip: "Can't hear, and fails any check that requires hearing.",
+        attacking: None, attacked: None, checks: None,
+        incapacitates: false, crit_against: false,
+        fails_saves: false, dex_saves: None
+    },
+    Condition {
+        name: "exhaustion",
+        descrip: "Comes in six levels, each worse than the last; only the first (disadvantage on checks) is applied here.",
+        attacking: None, attacked: None, checks: Some(Disadvantage),
+        incapacitates: false, crit_against: false,
+        fails_saves: false, dex_saves: None
+    },
+    Condition {
+        name: "frightened",
+        descrip: "Disadvantage on checks and attacks while the source of its fear is in sight.",
+        attacking: Some(Disadvantage), attacked: None, checks: Some(Disadvantage),
+        incapacitates: false, crit_against: false,
+        fails_saves: false, dex_saves: None
+    },
+    Condition {
+        name: "grappled",
+        descrip: "Speed becomes 0.",
+        attacking: None, attacked: None, checks: None,
+        incapacitates: false, crit_against: false,
+        fails_saves: false, dex_saves: None
+    },
+    Condition {
+        name: "incapacitated",
+        descrip: "Can't take actions or reactions.",
+        attacking: None, attacked: None, checks: None,
+        incapacitates: true, crit_against: false,
+        fails_saves: false, dex_saves: None
+    },
+    Condition {
+        name: "invisible",
+        descrip: "Can't be seen. Attacks against it have disadvantage; its attacks have advantage.",
+        attacking: Some(Advantage), attacked: Some(Disadvantage), checks: None,
+        incapacitates: false, crit_against: false,
+        fails_saves: false, dex_saves: None
+    },
+    Condition {
+        name: "paralyzed",
+        descrip: "Incapacitated; fails Str and Dex saves. Attacks against it have advantage, and hits are critical hits.",
+        attacking: None, attacked: Some(Advantage), checks: None,
+        incapacitates: true, crit_against: true,
+        fails_saves: true, dex_saves: None
+    },
+    Condition {
+        name: "petrified",
+        descrip: "Turned to stone and incapacitated; fails Str and Dex saves. Attacks against it have advantage.",
+        attacking: None, attacked: Some(Advantage), checks: None,
+        incapacitates: true, crit_against: false,
+        fails_saves: true, dex_saves: None
+    },
+    Condition {
+        name: "poisoned",
+        descrip: "Disadvantage on attack rolls and ability checks.",
+        attacking: Some(Disadvantage), attacked: None, checks: Some(Disadvantage),
+        incapacitates: false, crit_against: false,
+        fails_saves: false, dex_saves: None
+    },
+    Condition {
+        name: "prone",
+        descrip: "Its attacks have disadvantage; attacks against it from within 5 feet have advantage.",
+        attacking: Some(Disadvantage), attacked: Some(Advantage), checks: None,
+        incapacitates: false, crit_against: false,
+        fails_saves: false, dex_saves: None
+    },
+    Condition {
+        name: "restrained",
+        descrip: "Speed 0. Attacks against it have advantage; its attacks and Dex saves have disadvantage.",
+        attacking: Some(Disadvantage), attacked: Some(Advantage), checks: None,
+        incapacitates: false, crit_against: false,
+        fails_saves: false, dex_saves: Some(Disadvantage)
+    },
+    Condition {
+        name: "stunned",
+        descrip: "Incapacitated; fails Str and Dex saves. Attacks against it have advantage.",
+        attacking: None, attacked: Some(Advantage), checks: None,
+        incapacitates: true, crit_against: false,
+        fails_saves: true, dex_saves: None
+    },
+    Condition {
+        name: "unconscious",
+        descrip: "Incapacitated and prone; fails Str and Dex saves. Attacks against it have advantage, and hits are critical hits.",
+        attacking: None, attacked: Some(Advantage), checks: None,
+        incapacitates: true, crit_against: true,
+        fails_saves: true, dex_saves: None
+    },
+];
+
+/// Finds a condition by its name, or by the start of its name if that's
+/// enough to tell which one is meant.
+pub fn find(name: &str) -> Result<&'static Condition, String> {
+    let name = name.to_lowercase();
+    if let Some(c) = CONDITIONS.iter().find(|c| c.name == name) {
+        return Ok(c);
+    }
+    let matches = CONDITIONS.iter()
+        .filter(|c| name != "" && c.name.starts_with(&name as &str))
+        .collect::<Vec<_>>();
+    match matches.len() {
+        0 => Err("No such condition.".into()),
+        1 => Ok(matches[0]),
+        _ => Err(format!("That could be any of: {}.", matches.iter().map(|c| c.name).collect::<Vec<_>>().join(", ")))
+    }
+}
+/// The conditions in a list of (full) condition names.
+fn active(conds: &[String]) -> Vec<&'static Condition> {
+    conds.iter().filter_map(|c| CONDITIONS.iter().find(|x| x.name == *c)).collect()
+}
+/// Advantage/disadvantage on a creature's attacks, with reasons.
+pub fn attacking(conds: &[String]) -> Vec<(RollMode, String)> {
+    active(conds).into_iter()
+        .filter_map(|c| c.attacking.map(|m| (m, format!("{} from being <b>{}</b>", mode_name(m), c.name))))
+        .collect()
+}
+/// Advantage/disadvantage on attacks against a creature, with reasons.
+pub fn attacked(conds: &[String]) -> Vec<(RollMode, String)> {
+    active(conds).into_iter()
+        .filter_map(|c| c.attacked.map(|m| (m, format!("{} against a <b>{}</b> target", mode_name(m), c.name))))
+        .collect()
+}
+/// Advantage/disadvantage on a creature's ability checks, with reasons.
+pub fn checks(conds: &[String]) -> Vec<(RollMode, String)> {
+    active(conds).into_iter()
+        .filter_map(|c| c.checks.map(|m| (m, format!("{} from being <b>{}</b>", mode_name(m), c.name))))
+        .collect()
+}
+/// Advantage/disadvantage on a creature's saving throws of an ability, with reasons.
+pub fn saves(conds: &[String], ability: &str) -> Vec<(RollMode, String)> {
+    if ability != "dex" {
+        return vec![];
+    }
+    active(conds).into_iter()
+        .filter_map(|c| c.dex_saves.map(|m| (m, format!("{} from being <b>{}</b>", mode_name(m), c.name))))
+        .collect()
+}
+/// The condition (if any) making a creature fail saving throws of an ability.
+pub fn fails_save(conds: &[String], ability: &str) -> Option<&'static str> {
+    if ability != "str" && ability != "dex" {
+        return None;
+    }
+    active(conds).into_iter().find(|c| c.fails_saves).map(|c| c.name)
+}
+/// The condition (if any) stopping a creature from acting.
+pub fn incapacitated(conds: &[String]) -> Option<&'static str> {
+    active(conds).into_iter().find(|c| c.incapacitates).map(|c| c.name)
+}
+/// The condition (if any) making hits against a creature critical.
+pub fn crit_against(conds: &[String]) -> Option<&'static str> {
+    active(conds).into_iter().find(|c| c.crit_against).map(|c| c.name)
+}
+fn mode_name(m: RollMode) -> &'static str {
+    match m {
+        Advantage => "advantage",
+        Disadvantage => "disadvantage",
+        Normal => "no change"
+    }
+}
@@ -0,0 +1,153 @@
+//! Damage types, and resistance, vulnerability and immunity to them.
+//!
+//! Attacks can say what type of damage they do after the dicespec, like
+//! `2d6+3 fire`, or `1d8+@str magical slashing` for a magic weapon (which
+//! gets past resistance to nonmagical weapons).
+use errors::*;
+use std::fmt;
+
+pub const DAMAGE_TYPES: &[&str] = &["acid", "bludgeoning", "cold", "fire", "force", "lightning",
+                                     "necrotic", "piercing", "poison", "psychic", "radiant",
+                                     "slashing", "thunder"];
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct DamageType {
+    pub name: &'static str,
+    pub magical: bool
+}
+impl fmt::Display for DamageType {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.magical {
+            write!(f, "magical ")?;
+        }
+        write!(f, "{}", self.name)
+    }
+}
+fn find_type(name: &str) -> Option<&'static str> {
+    let name = name.to_lowercase();
+    DAMAGE_TYPES.iter().find(|&&t| t == name).cloned()
+}
+/// Parses a damage type like `fire` or `magical slashing`.
+pub fn parse_type(spec: &str) -> Result<DamageType> {
+    let words = spec.split_whitespace().collect::<Vec<_>>();
+    let (magical, name) = match &words as &[_] {
+        &[name] => (false, name),
+        &[m, name] if m.to_lowercase() == "magical" => (true, name),
+        _ => bail!("Invalid damage type '{}'", spec)
+    };
+    let name = find_type(name).ok_or_else(|| format!("Unknown damage type '{}'", name))?;
+    Ok(DamageType { name, magical })
+}
+/// Splits an attack like `2d6+3 fire` into its dicespec and damage type (if any).
+pub fn split_attack(attack: &str) -> Result<(&str, Option<DamageType>)> {
+    let attack = attack.trim();
+    let mut start = attack.len();
+    // take off words from the end, as long as they're only letters
+    for _ in 0..2 {
+        let rest = attack[..start].trim_right();
+        let word_start = rest.rfind(char::is_whitespace).map(|i| i + 1).unwrap_or(0);
+        let word = &rest[word_start..];
+        if word_start == 0 || word == "" || !word.chars().all(char::is_alphabetic) {
+            break;
+        }
+        start = word_start;
+    }
+    if start == attack.len() {
+        return Ok((attack, None));
+    }
+    let typ = parse_type(&attack[start..])?;
+    Ok((attack[..start].trim_right(), Some(typ)))
+}
+/// Checks that an attack has a valid dicespec and damage type.
+pub fn validate_attack(attack: &str) -> Result<()> {
+    let (spec, _) = split_attack(attack)?;
+    ::dice::parse(spec)?;
+    Ok(())
+}
+/// Picks out the damage types from one of the SRD's damage resistance fields,
+/// like "cold; bludgeoning, piercing, and slashing from nonmagical weapons".
+///
+/// Types qualified with "nonmagical" (or "from magic") are stored with that in
+/// front of them, e.g. "nonmagical slashing".
+pub fn parse_srd(text: &str) -> Vec<String> {
+    let mut ret = vec![];
+    for clause in text.split(';') {
+        let prefix = if clause.contains("nonmagical") {
+            "nonmagical "
+        }
+        else if clause.contains("from magic") {
+            "magical "
+        }
+        else {
+            ""
+        };
+        for word in clause.split(|c: char| !c.is_alphabetic()) {
+            if let Some(t) = find_type(word) {
+                ret.push(format!("{}{}", prefix, t));
+            }
+        }
+    }
+    ret
+}
+/// Works out the damage type of an SRD action from its description
+/// (e.g. "Hit: 12 (2d6 + 5) bludgeoning damage.").
+///
+/// The SRD gives the dice for hits like "17 (2d10 + 6) piercing damage plus
+/// 4 (1d8) acid damage" all together, so if they're of different types the
+/// action is left untyped, rather than one type's resistances applying to
+/// all of the damage.
+pub fn from_description(desc: &str) -> Option<&'static str> {
+    let hit = match desc.find("Hit:") {
+        Some(h) => h,
+        None => return None
+    };
+    let sentence = desc[hit..].split('.').next().unwrap_or("");
+    let mut types = sentence.split(" plus ")
+        .filter_map(|part| part.split(|c: char| !c.is_alphabetic()).filter_map(find_type).next());
+    let first = types.next();
+    if types.any(|t| Some(t) != first) {
+        return None;
+    }
+    first
+}
+/// Finds the entry in a list of resistances (or similar) that covers a damage type.
+fn covers<'a>(typ: &DamageType, list: &'a [String]) -> Option<&'a String> {
+    list.iter().find(|entry| {
+        let mut words = entry.split_whitespace();
+        match (words.next(), words.next()) {
+            (Some(name), None) => name == typ.name,
+            (Some("nonmagical"), Some(name)) => name == typ.name && !typ.magical,
+            (Some("magical"), Some(name)) => name == typ.name && typ.magical,
+            _ => false
+        }
+    })
+}
+/// What a creature takes more, less or no damage from.
+pub struct Defenses<'a> {
+    pub vulnerabilities: &'a [String],
+    pub resistances: &'a [String],
+    pub immunities: &'a [String]
+}
+impl<'a> Defenses<'a> {
+    pub fn none() -> Defenses<'static> {
+        Defenses { vulnerabilities: &[], resistances: &[], immunities: &[] }
+    }
+    /// Adjusts `dmg` for a damage type, returning the new damage and why it changed.
+    pub fn apply(&self, dmg: i64, typ: &DamageType) -> (i64, Vec<String>) {
+        let mut reasons = vec![];
+        if let Some(e) = covers(typ, self.immunities) {
+            reasons.push(format!("immune to {} damage: no damage", e));
+            return (0, reasons);
+        }
+        let mut dmg = dmg;
+        if let Some(e) = covers(typ, self.resistances) {
+            dmg /= 2;
+            reasons.push(format!("resistant to {} damage: halved", e));
+        }
+        if let Some(e) = covers(typ, self.vulnerabilities) {
+            dmg *= 2;
+            reasons.push(format!("vulnerable to {} damage: doubled", e));
+        }
+        (dmg, reasons)
+    }
+}
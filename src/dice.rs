@@ -0,0 +1,876 @@
+//! Parsing and rolling of dicespecs, like `2d6+3`.
+//!
+//! On top of plain `NdM` terms and constants, dice terms can be followed by
+//! modifiers, applied in this order:
+//!
+//! - `r1`, `r<3`: reroll dice matching the comparison once (default: equal to)
+//! - `!`, `!>=5`: explode dice matching the comparison (default: the highest face)
+//! - `kh3`, `kl1`, `dh1`, `dl1`: keep/drop the highest/lowest N dice
+//!   (`k3` means `kh3`, and `d1` means `dl1`)
+//! - `>=7`, `<3`, `=10`...: count the dice matching the comparison as successes,
+//!   instead of adding them up
+//!
+//! Specs can also refer to the attributes of whoever's rolling, with `@str`,
+//! `@dex` etc. (ability modifiers), `@prof` (proficiency bonus) and `@level`.
+use rand::{Rng, SeedableRng};
+use rand::isaac::Isaac64Rng;
+use std::fmt;
+use std::error::Error;
+
+/// The most dice we'll roll in one term, so nobody can ask for `99999999d6`.
+pub const MAX_DICE: u32 = 1000;
+/// The most sides a die can have.
+pub const MAX_SIDES: u32 = 10000;
+/// The most times a single die can explode. The odds calculator follows
+/// explosions this far too, so keep it small enough for that to be quick.
+pub const MAX_EXPLOSIONS: u32 = 20;
+/// The attributes that can be referred to with `@`.
+pub const REFERENCES: &[&str] = &["str", "dex", "con", "int", "wis", "cha", "prof", "level"];
+
+/// Something that rolls dice, and has attributes for `@` references to look up.
+pub trait Attributes {
+    /// Looks up an attribute, given one of the names in `REFERENCES`.
+    fn attribute(&self, name: &str) -> Option<i64>;
+}
+/// For rolls that aren't made by anyone in particular.
+pub struct NoAttributes;
+impl Attributes for NoAttributes {
+    fn attribute(&self, _: &str) -> Option<i64> {
+        None
+    }
+}
+
+/// A seedable random number source, which keeps count of how many numbers
+/// it's handed out.
+///
+/// Knowing the seed and the draw count before a roll is enough to replay
+/// that roll exactly, with `DiceRng::replay`.
+pub struct DiceRng {
+    seed: u64,
+    draws: u64,
+    inner: Isaac64Rng
+}
+impl DiceRng {
+    pub fn new(seed: u64) -> DiceRng {
+        DiceRng {
+            seed: seed,
+            draws: 0,
+            inner: Isaac64Rng::from_seed(&[seed])
+        }
+    }
+    /// Makes a new `DiceRng` with a random seed.
+    pub fn from_entropy() -> DiceRng {
+        DiceRng::new(::rand::thread_rng().gen())
+    }
+    /// Makes a new `DiceRng` in the state another one with the given seed
+    /// was in after `draws` draws.
+    pub fn replay(seed: u64, draws: u64) -> DiceRng {
+        let mut ret = DiceRng::new(seed);
+        // Isaac64Rng makes one u64 for every draw, whichever sort of draw
+        // it is, so this gets us to exactly the same place.
+        for _ in 0..draws {
+            ret.next_u64();
+        }
+        ret
+    }
+    pub fn seed(&self) -> u64 {
+        self.seed
+    }
+    pub fn draws(&self) -> u64 {
+        self.draws
+    }
+}
+impl Rng for DiceRng {
+    fn next_u32(&mut self) -> u32 {
+        self.draws += 1;
+        self.inner.next_u32()
+    }
+    fn next_u64(&mut self) -> u64 {
+        self.draws += 1;
+        self.inner.next_u64()
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum CompareOp {
+    Eq,
+    Lt,
+    Le,
+    Gt,
+    Ge
+}
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Compare {
+    pub op: CompareOp,
+    pub value: i64
+}
+impl Compare {
+    pub fn matches(&self, x: i64) -> bool {
+        match self.op {
+            CompareOp::Eq => x == self.value,
+            CompareOp::Lt => x < self.value,
+            CompareOp::Le => x <= self.value,
+            CompareOp::Gt => x > self.value,
+            CompareOp::Ge => x >= self.value
+        }
+    }
+}
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Keep {
+    Highest(u32),
+    Lowest(u32),
+    DropHighest(u32),
+    DropLowest(u32)
+}
+#[derive(Clone, Debug, PartialEq)]
+pub struct DiceTerm {
+    pub count: u32,
+    pub sides: u32,
+    pub reroll: Option<Compare>,
+    pub explode: Option<Compare>,
+    pub keep: Option<Keep>,
+    pub success: Option<Compare>
+}
+#[derive(Clone, Debug, PartialEq)]
+pub enum Term {
+    /// `NdM`, possibly with modifiers.
+    Dice(DiceTerm),
+    /// A flat number.
+    Constant(i64),
+    /// An `@` reference to one of the roller's attributes.
+    Reference(String)
+}
+#[derive(Clone, Debug, PartialEq)]
+pub struct Part {
+    pub negative: bool,
+    pub term: Term
+}
+#[derive(Clone, Debug, PartialEq)]
+pub struct DiceSpec {
+    pub parts: Vec<Part>
+}
+/// The result of rolling a single die.
+#[derive(Clone, Debug)]
+pub struct Die {
+    pub value: i64,
+    /// What the die showed before it was rerolled, if it was.
+    pub rerolled_from: Option<i64>,
+    /// Whether this die exploded (i.e. caused another die to be rolled).
+    pub exploded: bool,
+    /// Whether this die was discarded by a keep/drop modifier.
+    pub dropped: bool,
+    /// Whether this die counted as a success, if we're counting them.
+    pub success: Option<bool>
+}
+#[derive(Clone, Debug)]
+pub enum RolledTerm {
+    Dice(Vec<Die>),
+    Constant(i64),
+    Reference(String, i64)
+}
+#[derive(Clone, Debug)]
+pub struct RolledPart {
+    pub negative: bool,
+    pub term: RolledTerm,
+    pub value: i64
+}
+#[derive(Clone, Debug)]
+pub struct DiceRoll {
+    pub spec: DiceSpec,
+    pub parts: Vec<RolledPart>,
+    pub total: i64
+}
+#[derive(Clone, Debug)]
+pub struct ParseError {
+    pub spec: String,
+    pub pos: usize,
+    pub reason: String
+}
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let rest = self.spec.chars().skip(self.pos).collect::<String>();
+        if self.spec.len() == 0 {
+            write!(f, "Invalid dicespec: {}", self.reason)
+        }
+        else if rest.len() == 0 {
+            write!(f, "Invalid dicespec '{}': {} at the end of the spec", self.spec, self.reason)
+        }
+        else {
+            write!(f, "Invalid dicespec '{}': {} at '{}'", self.spec, self.reason, rest)
+        }
+    }
+}
+impl Error for ParseError {
+    fn description(&self) -> &str {
+        "invalid dicespec"
+    }
+}
+#[derive(Clone, Debug)]
+pub struct ReferenceError {
+    pub name: String
+}
+impl fmt::Display for ReferenceError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Can't work out @{}: this roll isn't being made by a player or monster", self.name)
+    }
+}
+impl Error for ReferenceError {
+    fn description(&self) -> &str {
+        "unresolved dicespec reference"
+    }
+}
+struct Parser {
+    chars: Vec<char>,
+    pos: usize
+}
+type PResult<T> = ::std::result::Result<T, ParseError>;
+impl Parser {
+    fn peek(&self) -> Option<char> {
+        self.chars.get(self.pos).cloned()
+    }
+    fn eat(&mut self, c: char) -> bool {
+        if self.peek() == Some(c) {
+            self.pos += 1;
+            true
+        }
+        else {
+            false
+        }
+    }
+    fn error_at<T>(&self, pos: usize, reason: &str) -> PResult<T> {
+        Err(ParseError {
+            spec: self.chars.iter().cloned().collect(),
+            pos: pos,
+            reason: reason.into()
+        })
+    }
+    fn error<T>(&self, reason: &str) -> PResult<T> {
+        self.error_at(self.pos, reason)
+    }
+    fn number(&mut self) -> PResult<Option<u32>> {
+        let start = self.pos;
+        let mut ret: u32 = 0;
+        while let Some(d) = self.peek().and_then(|c| c.to_digit(10)) {
+            ret = match ret.checked_mul(10).and_then(|r| r.checked_add(d)) {
+                Some(r) => r,
+                None => return self.error_at(start, "number too large")
+            };
+            self.pos += 1;
+        }
+        if self.pos == start {
+            Ok(None)
+        }
+        else {
+            Ok(Some(ret))
+        }
+    }
+    fn expect_number(&mut self, what: &str) -> PResult<u32> {
+        match self.number()? {
+            Some(n) => Ok(n),
+            None => self.error(&format!("expected {}", what))
+        }
+    }
+    fn compare_op(&mut self) -> Option<CompareOp> {
+        if self.eat('>') {
+            Some(if self.eat('=') { CompareOp::Ge } else { CompareOp::Gt })
+        }
+        else if self.eat('<') {
+            Some(if self.eat('=') { CompareOp::Le } else { CompareOp::Lt })
+        }
+        else if self.eat('=') {
+            Some(CompareOp::Eq)
+        }
+        else {
+            None
+        }
+    }
+    /// Parses a comparison like `>=5`, where the operator may be left out
+    /// (in which case it's `default`).
+    fn compare(&mut self, default: CompareOp) -> PResult<Compare> {
+        let op = self.compare_op().unwrap_or(default);
+        let value = self.expect_number("a number to compare against")?;
+        Ok(Compare { op, value: value as i64 })
+    }
+    fn dice(&mut self, start: usize, count: u32) -> PResult<DiceTerm> {
+        let sides = if self.eat('%') {
+            100
+        }
+        else {
+            self.expect_number("the number of sides after 'd'")?
+        };
+        if count == 0 || count > MAX_DICE {
+            return self.error_at(start, &format!("can only roll between 1 and {} dice", MAX_DICE));
+        }
+        if sides == 0 || sides > MAX_SIDES {
+            return self.error_at(start, &format!("dice must have between 1 and {} sides", MAX_SIDES));
+        }
+        let mut term = DiceTerm {
+            count, sides,
+            reroll: None,
+            explode: None,
+            keep: None,
+            success: None
+        };
+        loop {
+            let modifier = self.pos;
+            match self.peek() {
+                Some('r') | Some('R') if term.reroll.is_none() => {
+                    self.pos += 1;
+                    term.reroll = Some(self.compare(CompareOp::Eq)?);
+                },
+                Some('!') if term.explode.is_none() => {
+                    self.pos += 1;
+                    let cmp = match self.peek() {
+                        Some(c) if c.is_digit(10) || c == '<' || c == '>' || c == '=' => {
+                            self.compare(CompareOp::Eq)?
+                        },
+                        _ => Compare { op: CompareOp::Eq, value: sides as i64 }
+                    };
+                    if (1..sides as i64 + 1).all(|x| cmp.matches(x)) {
+                        return self.error_at(modifier, "those dice would explode forever");
+                    }
+                    term.explode = Some(cmp);
+                },
+                Some(c @ 'k') | Some(c @ 'K') | Some(c @ 'd') | Some(c @ 'D') if term.keep.is_none() => {
+                    self.pos += 1;
+                    let keep = c == 'k' || c == 'K';
+                    let highest = match self.peek() {
+                        Some('h') | Some('H') => { self.pos += 1; true },
+                        Some('l') | Some('L') => { self.pos += 1; false },
+                        _ => keep
+                    };
+                    let n = self.expect_number("how many dice to keep or drop")?;
+                    term.keep = Some(match (keep, highest) {
+                        (true, true) => Keep::Highest(n),
+                        (true, false) => Keep::Lowest(n),
+                        (false, true) => Keep::DropHighest(n),
+                        (false, false) => Keep::DropLowest(n)
+                    });
+                },
+                Some('<') | Some('>') | Some('=') if term.success.is_none() => {
+                    let op = self.compare_op().unwrap();
+                    let value = self.expect_number("a number to compare against")?;
+                    term.success = Some(Compare { op, value: value as i64 });
+                },
+                _ => break
+            }
+        }
+        Ok(term)
+    }
+    fn reference(&mut self, start: usize) -> PResult<Term> {
+        let mut name = String::new();
+        while let Some(c) = self.peek() {
+            if !c.is_alphabetic() {
+                break;
+            }
+            name.extend(c.to_lowercase());
+            self.pos += 1;
+        }
+        let name = match &name as &str {
+            "strength" => "str",
+            "dexterity" => "dex",
+            "constitution" => "con",
+            "intelligence" => "int",
+            "wisdom" => "wis",
+            "charisma" => "cha",
+            "proficiency" => "prof",
+            x => x
+        }.to_string();
+        if !REFERENCES.contains(&(&name as &str)) {
+            return self.error_at(start, &format!("unknown reference (try one of @{})", REFERENCES.join(", @")));
+        }
+        Ok(Term::Reference(name))
+    }
+    fn term(&mut self) -> PResult<Term> {
+        let start = self.pos;
+        if self.eat('@') {
+            return self.reference(start);
+        }
+        let count = self.number()?;
+        if self.eat('d') || self.eat('D') {
+            Ok(Term::Dice(self.dice(start, count.unwrap_or(1))?))
+        }
+        else {
+            match count {
+                Some(n) => Ok(Term::Constant(n as i64)),
+                None => self.error("expected a number or some dice (like 2d6)")
+            }
+        }
+    }
+    fn spec(&mut self) -> PResult<DiceSpec> {
+        if self.chars.len() == 0 {
+            return self.error("nothing to roll");
+        }
+        let mut parts = vec![];
+        let mut negative = false;
+        match self.peek() {
+            Some('-') => {
+                negative = true;
+                self.pos += 1;
+            },
+            Some('+') => self.pos += 1,
+            _ => {}
+        }
+        loop {
+            let term = self.term()?;
+            parts.push(Part { negative, term });
+            match self.peek() {
+                None => break,
+                Some('+') => negative = false,
+                Some('-') => negative = true,
+                Some(_) => return self.error("expected '+' or '-'")
+            }
+            self.pos += 1;
+        }
+        Ok(DiceSpec { parts })
+    }
+}
+/// Parses a dicespec, ignoring any whitespace in it.
+pub fn parse(spec: &str) -> PResult<DiceSpec> {
+    let mut p = Parser {
+        chars: spec.chars().filter(|c| !c.is_whitespace()).collect(),
+        pos: 0
+    };
+    p.spec()
+}
+impl DiceTerm {
+    fn roll_one<R: Rng>(&self, rng: &mut R) -> Die {
+        let mut die = Die {
+            value: rng.gen_range(1, self.sides as i64 + 1),
+            rerolled_from: None,
+            exploded: false,
+            dropped: false,
+            success: None
+        };
+        if let Some(ref cmp) = self.reroll {
+            if cmp.matches(die.value) {
+                die.rerolled_from = Some(die.value);
+                die.value = rng.gen_range(1, self.sides as i64 + 1);
+            }
+        }
+        die
+    }
+    pub fn roll<R: Rng>(&self, rng: &mut R) -> Vec<Die> {
+        let mut dice = vec![];
+        for _ in 0..self.count {
+            let mut die = self.roll_one(rng);
+            let mut explosions = 0;
+            while let Some(ref cmp) = self.explode {
+                if explosions >= MAX_EXPLOSIONS || !cmp.matches(die.value) {
+                    break;
+                }
+                die.exploded = true;
+                dice.push(die);
+                die = self.roll_one(rng);
+                explosions += 1;
+            }
+            dice.push(die);
+        }
+        if let Some(keep) = self.keep {
+            let mut order = (0..dice.len()).collect::<Vec<_>>();
+            order.sort_by_key(|&i| dice[i].value);
+            let len = dice.len();
+            let (lowest, highest) = match keep {
+                Keep::Highest(n) => (len.saturating_sub(n as usize), 0),
+                Keep::Lowest(n) => (0, len.saturating_sub(n as usize)),
+                Keep::DropHighest(n) => (0, n as usize),
+                Keep::DropLowest(n) => (n as usize, 0)
+            };
+            for &i in order.iter().take(lowest) {
+                dice[i].dropped = true;
+            }
+            for &i in order.iter().rev().take(highest) {
+                dice[i].dropped = true;
+            }
+        }
+        if let Some(ref cmp) = self.success {
+            for d in dice.iter_mut() {
+                if !d.dropped {
+                    d.success = Some(cmp.matches(d.value));
+                }
+            }
+        }
+        dice
+    }
+    /// The value of some dice rolled for this term: either their sum, or the
+    /// number of successes.
+    pub fn value(&self, dice: &[Die]) -> i64 {
+        let kept = dice.iter().filter(|d| !d.dropped);
+        if self.success.is_some() {
+            kept.filter(|d| d.success == Some(true)).count() as i64
+        }
+        else {
+            kept.map(|d| d.value).sum()
+        }
+    }
+}
+impl DiceSpec {
+    /// Just the dice in the spec, without any flat numbers or `@` references;
+    /// this is what gets rolled again for a critical hit.
+    pub fn dice_only(&self) -> DiceSpec {
+        let parts = self.parts.iter()
+            .filter(|p| match p.term {
+                Term::Dice(_) => true,
+                _ => false
+            })
+            .cloned()
+            .collect();
+        DiceSpec { parts }
+    }
+    pub fn roll<R: Rng, A: Attributes>(&self, rng: &mut R, attrs: &A) -> ::std::result::Result<DiceRoll, ReferenceError> {
+        let mut total = 0;
+        let mut parts = vec![];
+        for p in self.parts.iter() {
+            let (term, value) = match p.term {
+                Term::Dice(ref dt) => {
+                    let dice = dt.roll(rng);
+                    let value = dt.value(&dice);
+                    (RolledTerm::Dice(dice), value)
+                },
+                Term::Constant(n) => (RolledTerm::Constant(n), n),
+                Term::Reference(ref name) => {
+                    let value = attrs.attribute(name)
+                        .ok_or_else(|| ReferenceError { name: name.clone() })?;
+                    (RolledTerm::Reference(name.clone(), value), value)
+                }
+            };
+            if p.negative {
+                total -= value;
+            }
+            else {
+                total += value;
+            }
+            parts.push(RolledPart { negative: p.negative, term, value });
+        }
+        Ok(DiceRoll {
+            spec: self.clone(),
+            parts: parts,
+            total: total
+        })
+    }
+}
+impl DiceRoll {
+    /// The number showing on the d20 of a roll like `1d20+5`, `2d20kh1` or
+    /// `1d20r1`, if this was one.
+    pub fn natural_d20(&self) -> Option<i64> {
+        let mut ret = None;
+        for (sp, rp) in self.spec.parts.iter().zip(self.parts.iter()) {
+            if let (&Term::Dice(ref dt), &RolledTerm::Dice(ref dice)) = (&sp.term, &rp.term) {
+                if ret.is_some() || sp.negative || dt.sides != 20 || dt.success.is_some() {
+                    return None;
+                }
+                let mut kept = dice.iter().filter(|d| !d.dropped);
+                match (kept.next(), kept.next()) {
+                    (Some(d), None) => ret = Some(d.value),
+                    _ => return None
+                }
+            }
+        }
+        ret
+    }
+}
+impl fmt::Display for Compare {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let op = match self.op {
+            CompareOp::Eq => "=",
+            CompareOp::Lt => "<",
+            CompareOp::Le => "<=",
+            CompareOp::Gt => ">",
+            CompareOp::Ge => ">="
+        };
+        write!(f, "{}{}", op, self.value)
+    }
+}
+impl fmt::Display for DiceTerm {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}d{}", self.count, self.sides)?;
+        if let Some(ref cmp) = self.reroll {
+            match cmp.op {
+                CompareOp::Eq => write!(f, "r{}", cmp.value)?,
+                _ => write!(f, "r{}", cmp)?
+            }
+        }
+        if let Some(ref cmp) = self.explode {
+            match cmp.op {
+                CompareOp::Eq if cmp.value == self.sides as i64 => write!(f, "!")?,
+                CompareOp::Eq => write!(f, "!{}", cmp.value)?,
+                _ => write!(f, "!{}", cmp)?
+            }
+        }
+        match self.keep {
+            Some(Keep::Highest(n)) => write!(f, "kh{}", n)?,
+            Some(Keep::Lowest(n)) => write!(f, "kl{}", n)?,
+            Some(Keep::DropHighest(n)) => write!(f, "dh{}", n)?,
+            Some(Keep::DropLowest(n)) => write!(f, "dl{}", n)?,
+            None => {}
+        }
+        if let Some(ref cmp) = self.success {
+            write!(f, "{}", cmp)?;
+        }
+        Ok(())
+    }
+}
+impl fmt::Display for Term {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Term::Dice(ref dt) => write!(f, "{}", dt),
+            Term::Constant(n) => write!(f, "{}", n),
+            Term::Reference(ref name) => write!(f, "@{}", name)
+        }
+    }
+}
+impl fmt::Display for DiceSpec {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (i, p) in self.parts.iter().enumerate() {
+            if p.negative {
+                write!(f, "-")?;
+            }
+            else if i != 0 {
+                write!(f, "+")?;
+            }
+            write!(f, "{}", p.term)?;
+        }
+        Ok(())
+    }
+}
+/// Displays a die: rerolls are shown as `<s>1</s>4`, explosions as `6!`,
+/// dropped dice are struck out and successes are in bold.
+impl fmt::Display for Die {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if let Some(old) = self.rerolled_from {
+            write!(f, "<s>{}</s>", old)?;
+        }
+        match (self.dropped, self.success) {
+            (true, _) => write!(f, "<s>{}</s>", self.value)?,
+            (false, Some(true)) => write!(f, "<b>{}</b>", self.value)?,
+            _ => write!(f, "{}", self.value)?
+        }
+        if self.exploded {
+            write!(f, "!")?;
+        }
+        Ok(())
+    }
+}
+impl fmt::Display for RolledTerm {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            RolledTerm::Dice(ref dice) => {
+                let dice = dice.iter().map(|d| d.to_string()).collect::<Vec<_>>();
+                write!(f, "[{}]", dice.join(", "))
+            },
+            RolledTerm::Constant(n) => write!(f, "{}", n),
+            RolledTerm::Reference(ref name, n) => write!(f, "@{}({})", name, n)
+        }
+    }
+}
+/// Displays the roll in full, like `2d6+3: [4, 1] + 3 = 8`.
+impl fmt::Display for DiceRoll {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}: ", self.spec)?;
+        for (i, p) in self.parts.iter().enumerate() {
+            match (i, p.negative) {
+                (0, true) => write!(f, "-")?,
+                (0, false) => {},
+                (_, true) => write!(f, " - ")?,
+                (_, false) => write!(f, " + ")?
+            }
+            write!(f, "{}", p.term)?;
+        }
+        write!(f, " = {}", self.total)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn replay_repeats_a_roll() {
+        let spec = parse("4d6kh3+2").unwrap();
+        let mut rng = DiceRng::new(1234);
+        spec.roll(&mut rng, &NoAttributes).unwrap();
+        let draws = rng.draws();
+        let first = spec.roll(&mut rng, &NoAttributes).unwrap();
+        let mut replayed = DiceRng::replay(1234, draws);
+        assert_eq!(replayed.draws(), draws);
+        let second = spec.roll(&mut replayed, &NoAttributes).unwrap();
+        assert_eq!(first.total, second.total);
+        assert_eq!(first.to_string(), second.to_string());
+        assert_eq!(rng.draws(), replayed.draws());
+    }
+
+    fn dice_term(spec: &str) -> DiceTerm {
+        match parse(spec).unwrap().parts.remove(0).term {
+            Term::Dice(dt) => dt,
+            t => panic!("{} isn't a dice term: {:?}", spec, t)
+        }
+    }
+    fn die(value: i64) -> Die {
+        Die { value, rerolled_from: None, exploded: false, dropped: false, success: None }
+    }
+
+    #[test]
+    fn parses_plain_specs() {
+        let spec = parse("2d6 + 3 - @dex").unwrap();
+        assert_eq!(spec.parts.len(), 3);
+        assert_eq!(spec.parts[1], Part { negative: false, term: Term::Constant(3) });
+        assert_eq!(spec.parts[2], Part { negative: true, term: Term::Reference("dex".into()) });
+        assert_eq!(spec.to_string(), "2d6+3-@dex");
+        assert_eq!(parse("d%").unwrap().to_string(), "1d100");
+        assert_eq!(parse("@Strength").unwrap().to_string(), "@str");
+    }
+    #[test]
+    fn parses_keep_and_drop() {
+        assert_eq!(dice_term("4d6kh3").keep, Some(Keep::Highest(3)));
+        assert_eq!(dice_term("4d6k3").keep, Some(Keep::Highest(3)));
+        assert_eq!(dice_term("2d20kl1").keep, Some(Keep::Lowest(1)));
+        assert_eq!(dice_term("4d6dl1").keep, Some(Keep::DropLowest(1)));
+        assert_eq!(dice_term("4d6d1").keep, Some(Keep::DropLowest(1)));
+        assert_eq!(dice_term("4d6dh1").keep, Some(Keep::DropHighest(1)));
+    }
+    #[test]
+    fn parses_explode() {
+        assert_eq!(dice_term("3d6!").explode, Some(Compare { op: CompareOp::Eq, value: 6 }));
+        assert_eq!(dice_term("3d6!5").explode, Some(Compare { op: CompareOp::Eq, value: 5 }));
+        assert_eq!(dice_term("3d6!>=5").explode, Some(Compare { op: CompareOp::Ge, value: 5 }));
+        assert_eq!(parse("3d6!").unwrap().to_string(), "3d6!");
+    }
+    #[test]
+    fn parses_reroll() {
+        assert_eq!(dice_term("2d6r1").reroll, Some(Compare { op: CompareOp::Eq, value: 1 }));
+        assert_eq!(dice_term("2d6r<3").reroll, Some(Compare { op: CompareOp::Lt, value: 3 }));
+        assert_eq!(parse("2d6r<3").unwrap().to_string(), "2d6r<3");
+    }
+    #[test]
+    fn parses_success() {
+        assert_eq!(dice_term("8d10>=7").success, Some(Compare { op: CompareOp::Ge, value: 7 }));
+        assert_eq!(dice_term("8d10=10").success, Some(Compare { op: CompareOp::Eq, value: 10 }));
+        let dt = dice_term("10d10r1!kh5>7");
+        assert_eq!(dt.reroll, Some(Compare { op: CompareOp::Eq, value: 1 }));
+        assert_eq!(dt.explode, Some(Compare { op: CompareOp::Eq, value: 10 }));
+        assert_eq!(dt.keep, Some(Keep::Highest(5)));
+        assert_eq!(dt.success, Some(Compare { op: CompareOp::Gt, value: 7 }));
+    }
+    #[test]
+    fn rejects_malformed_specs() {
+        let bad = &[
+            "", "d", "2d", "2d6+", "2d6x", "+", "2d0", "0d6", "1001d6", "1d10001",
+            "99999999999d6", "2d6kh", "2d6r", "2d6!>", "1d1!", "3d6!>=1", "@foo", "2d6>"
+        ];
+        for spec in bad.iter() {
+            assert!(parse(spec).is_err(), "{} should be rejected", spec);
+        }
+        let err = parse("2d6x").unwrap_err();
+        assert_eq!(err.pos, 3);
+        assert_eq!(err.to_string(), "Invalid dicespec '2d6x': expected '+' or '-' at 'x'");
+    }
+    #[test]
+    fn values_kept_dice() {
+        let dt = dice_term("4d6kh3");
+        let mut dice = vec![die(3), die(1), die(6), die(4)];
+        dice[1].dropped = true;
+        assert_eq!(dt.value(&dice), 13);
+    }
+    #[test]
+    fn values_successes() {
+        let dt = dice_term("4d10>=7");
+        let mut dice = vec![die(7), die(10), die(2), die(8)];
+        dice[2].success = Some(false);
+        dice[3].dropped = true;
+        dice[0].success = Some(true);
+        dice[1].success = Some(true);
+        assert_eq!(dt.value(&dice), 2);
+    }
+    #[test]
+    fn keeps_the_right_dice() {
+        let mut rng = DiceRng::new(42);
+        for spec in &["4d6kh3", "4d6dl1", "4d6kl1", "4d6dh2"] {
+            let dt = dice_term(spec);
+            for _ in 0..100 {
+                let dice = dt.roll(&mut rng);
+                let (kept, dropped): (Vec<_>, Vec<_>) = dice.iter().partition(|d| !d.dropped);
+                let expected = match dt.keep.unwrap() {
+                    Keep::Highest(n) | Keep::Lowest(n) => n as usize,
+                    Keep::DropHighest(n) | Keep::DropLowest(n) => 4 - n as usize
+                };
+                assert_eq!(kept.len(), expected);
+                let highest = match dt.keep.unwrap() {
+                    Keep::Highest(_) | Keep::DropLowest(_) => true,
+                    _ => false
+                };
+                for k in kept.iter() {
+                    for d in dropped.iter() {
+                        if highest {
+                            assert!(k.value >= d.value);
+                        }
+                        else {
+                            assert!(k.value <= d.value);
+                        }
+                    }
+                }
+                assert_eq!(dt.value(&dice), kept.iter().map(|d| d.value).sum::<i64>());
+            }
+        }
+    }
+    #[test]
+    fn explodes_until_it_stops() {
+        let mut rng = DiceRng::new(42);
+        let dt = dice_term("5d6!>=5");
+        for _ in 0..100 {
+            let dice = dt.roll(&mut rng);
+            assert!(dice.len() >= 5);
+            // every die but the last from each original die exploded
+            assert_eq!(dice.iter().filter(|d| !d.exploded).count(), 5);
+            for d in dice.iter() {
+                assert_eq!(d.exploded, d.value >= 5);
+            }
+        }
+    }
+    #[test]
+    fn rerolls_once() {
+        let mut rng = DiceRng::new(42);
+        let dt = dice_term("10d6r<3");
+        let mut rerolled = 0;
+        for _ in 0..100 {
+            for d in dt.roll(&mut rng) {
+                match d.rerolled_from {
+                    Some(old) => {
+                        assert!(old < 3);
+                        rerolled += 1;
+                    },
+                    None => assert!(d.value >= 3)
+                }
+            }
+        }
+        assert!(rerolled > 0);
+    }
+    #[test]
+    fn finds_natural_d20s() {
+        let mut rng = DiceRng::new(42);
+        for spec in &["1d20", "1d20+5", "2d20kh1-2", "2d20kl1", "1d20r1"] {
+            let roll = parse(spec).unwrap().roll(&mut rng, &NoAttributes).unwrap();
+            let kept = match roll.parts[0].term {
+                RolledTerm::Dice(ref dice) => dice.iter().find(|d| !d.dropped).unwrap().value,
+                _ => unreachable!()
+            };
+            assert_eq!(roll.natural_d20(), Some(kept), "{}", spec);
+        }
+        for spec in &["2d20", "1d20+1d4", "1d12", "-1d20", "3d20>=10", "5"] {
+            let roll = parse(spec).unwrap().roll(&mut rng, &NoAttributes).unwrap();
+            assert_eq!(roll.natural_d20(), None, "{}", spec);
+        }
+    }
+    #[test]
+    fn counts_successes() {
+        let mut rng = DiceRng::new(42);
+        let dt = dice_term("8d10>=7");
+        for _ in 0..100 {
+            let dice = dt.roll(&mut rng);
+            for d in dice.iter() {
+                assert_eq!(d.success, Some(d.value >= 7));
+            }
+            let n = dice.iter().filter(|d| d.value >= 7).count() as i64;
+            assert_eq!(dt.value(&dice), n);
+        }
+    }
+}
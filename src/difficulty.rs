@@ -0,0 +1,306 @@
+//! Working out how hard an encounter is, using the XP thresholds and
+//! multipliers from the DMG.
+
+/// XP thresholds for one character of each level: easy, medium, hard and deadly.
+const THRESHOLDS: &[[i32; 4]] = &[
+    [25, 50, 75, 100],
+    [50, 100, 150, 200],
+    [75, 150, 225, 400],
+    [125, 250, 375, 500],
+    [250, 500, 750, 1100],
+    [300, 600, 900, 1400],
+    [350, 750, 1100, 1700],
+    [450, 900, 1400, 2100],
+    [550, 1100, 1600, 2400],
+    [600, 1200, 1900, 2800],
+    [800, 1600, 2400, 3600],
+    [1000, 2000, 3000, 4500],
+    [1100, 2200, 3400, 5100],
+    [1250, 2500, 3800, 5700],
+    [1400, 2800, 4300, 6400],
+    [1600, 3200, 4800, 7200],
+    [2000, 3900, 5900, 8800],
+    [2100, 4200, 6300, 9500],
+    [2400, 4900, 7300, 10900],
+    [2800, 5700, 8500, 12700],
+];
+pub const RATINGS: &[&str] = &["easy", "medium", "hard", "deadly"];
+/// Encounter multipliers, from the one for a lone monster facing a big party
+/// to the one for a horde facing a small party.
+const MULTIPLIERS: &[f64] = &[0.5, 1.0, 1.5, 2.0, 2.5, 3.0, 4.0, 5.0];
+
+/// The XP thresholds for a character of some level.
+pub fn thresholds(level: i32) -> [i32; 4] {
+    let idx = ::std::cmp::min(::std::cmp::max(level, 1), 20) - 1;
+    THRESHOLDS[idx as usize]
+}
+/// How much to multiply the monsters' XP by, given how many of them there are.
+///
+/// Parties of fewer than three use the next multiplier up, and parties of six
+/// or more the next one down.
+pub fn multiplier(n_monsters: usize, n_players: usize) -> f64 {
+    let idx = match n_monsters {
+        0 | 1 => 1,
+        2 => 2,
+        3...6 => 3,
+        7...10 => 4,
+        11...14 => 5,
+        _ => 6
+    };
+    let idx = if n_players < 3 {
+        idx + 1
+    }
+    else if n_players >= 6 {
+        idx - 1
+    }
+    else {
+        idx
+    };
+    MULTIPLIERS[idx]
+}
+pub struct Difficulty {
+    pub total_xp: i32,
+    pub multiplier: f64,
+    pub adjusted_xp: i32,
+    /// The party's thresholds, added up.
+    pub thresholds: [i32; 4],
+    /// The highest threshold reached, or "trivial" if none are.
+    pub rating: &'static str
+}
+/// Rates an encounter, given the XP of each monster and the level of each player.
+pub fn rate(monster_xp: &[i32], levels: &[i32]) -> Difficulty {
+    let total_xp: i32 = monster_xp.iter().sum();
+    let multiplier = multiplier(monster_xp.len(), levels.len());
+    let adjusted_xp = (total_xp as f64 * multiplier) as i32;
+    let mut party = [0; 4];
+    for &l in levels {
+        let t = thresholds(l);
+        for i in 0..4 {
+            party[i] += t[i];
+        }
+    }
+    let rating = RATINGS.iter().zip(party.iter())
+        .filter(|&(_, &t)| adjusted_xp >= t)
+        .map(|(&r, _)| r)
+        .last()
+        .unwrap_or("trivial");
+    Difficulty { total_xp, multiplier, adjusted_xp, thresholds: party, rating }
+}
+/// The most monsters of one kind the encounter generator puts in a group.
+pub const MAX_GROUP: usize = 8;
+/// The most kinds of monster the encounter generator mixes together.
+pub const MAX_KINDS: usize = 3;
+/// The most monsters the encounter generator puts in an encounter.
+pub const MAX_MONSTERS: usize = 12;
+/// How many mixes the encounter generator tries before settling for the
+/// closest one.
+const TRIES: usize = 20;
+
+/// The range of adjusted XP that makes an encounter a given difficulty, given
+/// the party's thresholds.
+pub fn xp_range(thresholds: &[i32; 4], rating: &str) -> Option<(i32, i32)> {
+    let idx = match RATINGS.iter().position(|&r| r == rating) {
+        Some(i) => i,
+        None => return None
+    };
+    let upper = match thresholds.get(idx + 1) {
+        Some(t) => t - 1,
+        // there's no upper limit on deadly, but let's not go overboard
+        None => thresholds[idx] * 3 / 2
+    };
+    Some((thresholds[idx], upper))
+}
+/// The adjusted XP of a group of identical monsters.
+pub fn group_xp(xp: i32, n_monsters: usize, n_players: usize) -> i32 {
+    (xp as f64 * n_monsters as f64 * multiplier(n_monsters, n_players)) as i32
+}
+/// The largest group (up to `MAX_GROUP`) of monsters worth `xp` each that
+/// doesn't go over `upper` adjusted XP; 0 if even one is too many.
+pub fn group_size(xp: i32, n_players: usize, upper: i32) -> usize {
+    (1..MAX_GROUP + 1)
+        .take_while(|&n| group_xp(xp, n, n_players) <= upper)
+        .last()
+        .unwrap_or(0)
+}
+/// The adjusted XP of some monsters, given as indices into `xps`.
+fn picked_xp(xps: &[i32], picked: &[usize], n_players: usize) -> i32 {
+    let total: i32 = picked.iter().map(|&i| xps[i]).sum();
+    (total as f64 * multiplier(picked.len(), n_players)) as i32
+}
+/// Picks a mix of monsters whose adjusted XP lands in `range`, given the XP
+/// of each kind of monster that could be in it. `draw(n)` should pick a
+/// number from 0 to n - 1.
+///
+/// Returns the monsters picked as indices into `xps`, with the same kinds
+/// next to each other; if the range can't be reached, this is the closest
+/// mix found that doesn't go over it.
+pub fn fill_budget<F: FnMut(usize) -> usize>(xps: &[i32], n_players: usize, range: (i32, i32), mut draw: F) -> Vec<usize> {
+    let (min, max) = range;
+    let mut best = vec![];
+    let mut best_xp = 0;
+    for _ in 0..TRIES {
+        let mut picked: Vec<usize> = vec![];
+        while picked.len() < MAX_MONSTERS {
+            let mut kinds = picked.clone();
+            kinds.sort();
+            kinds.dedup();
+            let fits = (0..xps.len())
+                .filter(|&i| kinds.contains(&i) || kinds.len() < MAX_KINDS)
+                .filter(|&i| picked.iter().filter(|&&p| p == i).count() < MAX_GROUP)
+                .filter(|&i| {
+                    let mut with = picked.clone();
+                    with.push(i);
+                    picked_xp(xps, &with, n_players) <= max
+                })
+                .collect::<Vec<_>>();
+            if fits.len() == 0 {
+                break;
+            }
+            // half the time, add to one of the groups we've already got
+            let again = fits.iter().cloned().filter(|i| kinds.contains(i)).collect::<Vec<_>>();
+            let pool = if again.len() > 0 && draw(2) == 0 { again } else { fits };
+            picked.push(pool[draw(pool.len())]);
+            if picked_xp(xps, &picked, n_players) >= min {
+                break;
+            }
+        }
+        let xp = picked_xp(xps, &picked, n_players);
+        if xp > best_xp {
+            best = picked;
+            best_xp = xp;
+        }
+        if best_xp >= min {
+            break;
+        }
+    }
+    best.sort();
+    best
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn looks_up_thresholds() {
+        let cases = &[
+            (1, [25, 50, 75, 100]),
+            (5, [250, 500, 750, 1100]),
+            (12, [1000, 2000, 3000, 4500]),
+            (20, [2800, 5700, 8500, 12700]),
+            // levels out of range use the nearest one there is
+            (0, [25, 50, 75, 100]),
+            (25, [2800, 5700, 8500, 12700]),
+        ];
+        for &(level, expected) in cases {
+            assert_eq!(thresholds(level), expected, "level {}", level);
+        }
+    }
+    #[test]
+    fn multiplies_by_group_size() {
+        let cases = &[
+            (1, 1.0), (2, 1.5),
+            (3, 2.0), (6, 2.0),
+            (7, 2.5), (10, 2.5),
+            (11, 3.0), (14, 3.0),
+            (15, 4.0), (30, 4.0),
+        ];
+        for &(n, expected) in cases {
+            assert_eq!(multiplier(n, 4), expected, "{} monsters", n);
+        }
+    }
+    #[test]
+    fn adjusts_multiplier_for_party_size() {
+        let cases = &[
+            // (monsters, players, multiplier)
+            (1, 1, 1.5), (1, 2, 1.5), (1, 3, 1.0), (1, 5, 1.0), (1, 6, 0.5), (1, 8, 0.5),
+            (2, 2, 2.0), (2, 6, 1.0),
+            (3, 2, 2.5), (3, 6, 1.5),
+            (7, 2, 3.0), (7, 6, 2.0),
+            (11, 2, 4.0), (11, 6, 2.5),
+            (15, 2, 5.0), (15, 6, 3.0),
+        ];
+        for &(n, players, expected) in cases {
+            assert_eq!(multiplier(n, players), expected, "{} monsters, {} players", n, players);
+        }
+    }
+    #[test]
+    fn rates_encounters() {
+        let cases: &[(&[i32], &[i32], i32, &str)] = &[
+            // (monster XP, player levels, adjusted XP, rating)
+            (&[], &[1, 1, 1, 1], 0, "trivial"),
+            (&[50], &[1, 1, 1, 1], 50, "trivial"),
+            (&[100], &[1, 1, 1, 1], 100, "easy"),
+            (&[50, 50], &[1, 1, 1, 1], 150, "easy"),
+            (&[100, 100], &[1, 1, 1, 1], 300, "hard"),
+            (&[200, 200], &[1, 1, 1, 1], 600, "deadly"),
+            (&[450, 450], &[3, 3], 1800, "deadly"),
+            (&[1800], &[5, 5, 5, 5, 5, 5], 900, "trivial"),
+        ];
+        for &(xp, levels, adjusted, rating) in cases {
+            let d = rate(xp, levels);
+            assert_eq!((d.adjusted_xp, d.rating), (adjusted, rating), "{:?} against {:?}", xp, levels);
+        }
+        assert_eq!(rate(&[], &[1, 5]).thresholds, [275, 550, 825, 1200]);
+    }
+    #[test]
+    fn finds_xp_ranges() {
+        let t = [100, 200, 300, 400];
+        assert_eq!(xp_range(&t, "easy"), Some((100, 199)));
+        assert_eq!(xp_range(&t, "hard"), Some((300, 399)));
+        assert_eq!(xp_range(&t, "deadly"), Some((400, 600)));
+        assert_eq!(xp_range(&t, "trivial"), None);
+    }
+    #[test]
+    fn sizes_groups() {
+        let cases = &[
+            // (XP budget, group size): 100 XP monsters against 4 players
+            (99, 0),
+            (100, 1), (299, 1),
+            (300, 2),
+            (600, 3),
+            (1200, 6), (1749, 6),
+            (1750, 7),
+            (2000, 8),
+            (100000, MAX_GROUP),
+        ];
+        for &(upper, expected) in cases {
+            assert_eq!(group_size(100, 4, upper), expected, "{} XP", upper);
+        }
+        assert_eq!(group_xp(100, 7, 4), 1750);
+        assert_eq!(group_xp(100, 1, 6), 50);
+    }
+    /// A little random number generator, so the tests don't depend on `rand`.
+    fn draws(seed: u64) -> Box<FnMut(usize) -> usize> {
+        let mut state = seed;
+        Box::new(move |n| {
+            state = state.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+            ((state >> 33) % n as u64) as usize
+        })
+    }
+    #[test]
+    fn fills_budgets_with_a_mix() {
+        let xps = &[10, 25, 50, 100, 200, 450, 700, 1100, 1800];
+        let mut mixed = false;
+        for seed in 0..100 {
+            let picked = fill_budget(xps, 4, (900, 1399), draws(seed));
+            let xp = picked_xp(xps, &picked, 4);
+            assert!(xp >= 900 && xp <= 1399, "seed {}: {:?} is worth {} XP", seed, picked, xp);
+            assert!(picked.len() <= MAX_MONSTERS);
+            let mut kinds = picked.clone();
+            kinds.dedup();
+            assert!(kinds.len() <= MAX_KINDS, "seed {}: {:?}", seed, picked);
+            for &k in kinds.iter() {
+                assert!(picked.iter().filter(|&&p| p == k).count() <= MAX_GROUP);
+            }
+            mixed |= kinds.len() > 1;
+        }
+        assert!(mixed, "every encounter was one kind of monster");
+    }
+    #[test]
+    fn settles_for_the_closest_mix() {
+        // eight 10 XP monsters are as close as we can get without going over
+        assert_eq!(fill_budget(&[10], 4, (5000, 7499), draws(1)), vec![0; MAX_GROUP]);
+        assert_eq!(fill_budget(&[100], 4, (50, 99), draws(1)), vec![]);
+    }
+}
@@ -1,5 +1,5 @@
 use super::schema::monsters;
-use super::models::{NewItem, NewRoom, NewAbility, NewPlayer, NewMonster, NewBuff};
+use super::models::{NewItem, NewRoom, NewAbility, NewMonster, NewBuff, default_level};
 #[derive(Serialize, Deserialize)]
 pub struct Weapon {
     pub name: String,
@@ -10,8 +10,47 @@ pub struct Weapon {
     #[serde(default)]
     pub attack_bonus: Option<i32>,
     #[serde(default)]
+    pub to_hit: Option<String>,
+    #[serde(default)]
+    pub damage_type: Option<String>,
+    #[serde(default)]
     pub player_id: Option<i32>
 }
+/// A player in a datafile, who becomes a `NewPlayer` once we know their HP.
+#[derive(Serialize, Deserialize)]
+pub struct DatafilePlayer {
+    pub id: Option<i32>,
+    pub name: String,
+    pub nick: String,
+    pub typ: String,
+    pub armor_class: i32,
+    pub hit_points: i32,
+    pub strength: i32,
+    pub intelligence: i32,
+    pub dexterity: i32,
+    pub constitution: i32,
+    pub wisdom: i32,
+    pub charisma: i32,
+    #[serde(default)]
+    pub initiative_bonus: i32,
+    #[serde(default = "default_level")]
+    pub level: i32,
+    #[serde(default)]
+    pub damage_vulnerabilities: Vec<String>,
+    #[serde(default)]
+    pub damage_resistances: Vec<String>,
+    #[serde(default)]
+    pub damage_immunities: Vec<String>,
+    #[serde(default)]
+    pub save_proficiencies: Vec<String>,
+    #[serde(default)]
+    pub xp: i32,
+    /// Defaults to `hit_points`.
+    #[serde(default)]
+    pub cur_hp: Option<i32>,
+    #[serde(default)]
+    pub temp_hp: i32
+}
 #[derive(Serialize, Deserialize)]
 pub struct Datafile {
     #[serde(default)]
@@ -21,7 +60,7 @@ pub struct Datafile {
     #[serde(default)]
     pub abilities: Vec<NewAbility>,
     #[serde(default)]
-    pub players: Vec<NewPlayer>,
+    pub players: Vec<DatafilePlayer>,
     #[serde(default)]
     pub monsters: Vec<NewMonster>,
     #[serde(default)]
@@ -36,7 +75,9 @@ pub struct MonsterAbility {
     #[serde(default)]
     pub damage_dice: Option<String>,
     #[serde(default)]
-    pub attack_bonus: Option<i32>
+    pub attack_bonus: Option<i32>,
+    #[serde(default)]
+    pub damage_bonus: Option<i32>
 }
 #[derive(Serialize, Deserialize)]
 pub struct SrdMonster {
@@ -45,6 +86,8 @@ pub struct SrdMonster {
     pub typ: String,
     pub armor_class: i32,
     pub hit_points: i32,
+    #[serde(default)]
+    pub hit_dice: Option<String>,
     pub strength: i32,
     pub intelligence: i32,
     pub dexterity: i32,
@@ -53,6 +96,26 @@ pub struct SrdMonster {
     pub charisma: i32,
     pub challenge_rating: String,
     #[serde(default)]
+    pub damage_vulnerabilities: String,
+    #[serde(default)]
+    pub damage_resistances: String,
+    #[serde(default)]
+    pub damage_immunities: String,
+    #[serde(default)]
+    pub condition_immunities: String,
+    #[serde(default)]
+    pub strength_save: Option<i32>,
+    #[serde(default)]
+    pub dexterity_save: Option<i32>,
+    #[serde(default)]
+    pub constitution_save: Option<i32>,
+    #[serde(default)]
+    pub intelligence_save: Option<i32>,
+    #[serde(default)]
+    pub wisdom_save: Option<i32>,
+    #[serde(default)]
+    pub charisma_save: Option<i32>,
+    #[serde(default)]
     pub special_abilities: Vec<MonsterAbility>,
     #[serde(default)]
     pub actions: Vec<MonsterAbility>
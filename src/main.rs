@@ -1,6 +1,6 @@
 #![feature(slice_patterns, advanced_slice_patterns, unicode)]
 extern crate glitch_in_the_matrix as gm;
-extern crate rouler;
+extern crate rand;
 #[macro_use] extern crate diesel;
 #[macro_use] extern crate diesel_codegen;
 extern crate serde;
@@ -20,8 +20,6 @@ use diesel::pg::PgConnection;
 use diesel::ArrayExpressionMethods;
 use dotenv::dotenv;
 use std::env;
-use rouler::Roller;
-use std::panic;
 use gm::MatrixClient;
 use gm::types::*;
 
@@ -31,6 +29,8 @@ use std::io::prelude::*;
 use std_unicode::str::UnicodeStr;
 use std::rc::Rc;
 use std::cell::RefCell;
+use std::time::SystemTime;
+use rand::Rng;
 
 pub mod errors {
     error_chain! {
@@ -42,6 +42,8 @@ pub mod errors {
             Diesel(::diesel::result::Error);
             Io(::std::io::Error);
             Json(::serde_json::Error);
+            Dice(::dice::ParseError);
+            DiceReference(::dice::ReferenceError);
         }
         errors {
             Ketos(d: String) {
@@ -62,7 +64,14 @@ pub mod models;
 pub mod import;
 pub mod matrix;
 pub mod scripts;
-use import::{SrdMonster, Weapon, MonsterAbility, Datafile};
+pub mod dice;
+pub mod odds;
+pub mod conditions;
+pub mod damage;
+pub mod multiattack;
+pub mod combatlog;
+pub mod difficulty;
+use import::{SrdMonster, Weapon, MonsterAbility, Datafile, DatafilePlayer};
 use schema::combatants::dsl as cdsl;
 use schema::monsters::dsl as mdsl;
 use schema::abilities::dsl as adsl;
@@ -71,8 +80,17 @@ use schema::rooms::dsl as rdsl;
 use schema::items::dsl as idsl;
 use schema::spells::dsl as sdsl;
 use schema::buffs::dsl as bdsl;
+use schema::props::dsl as prdsl;
+use schema::rolls::dsl as rodsl;
+use schema::macros::dsl as madsl;
+use schema::effects::dsl as edsl;
+use schema::encounter_state::dsl as esdsl;
+use schema::combat_actions::dsl as cadsl;
+use schema::combat_changes::dsl as ccdsl;
+use schema::combatant_uses::dsl as cudsl;
 use models::*;
 use models::Room;
+use dice::{DiceRoll, DiceRng};
 
 sql_function!(lower, lower_t, (a: diesel::types::VarChar) -> diesel::types::VarChar);
 
@@ -82,14 +100,6 @@ pub fn establish_connection() -> PgConnection {
     PgConnection::establish(&database_url)
         .expect(&format!("Error connecting to {}", database_url))
 }
-pub fn roll_dice(spec: &str) -> Result<i64> {
-    panic::catch_unwind(|| {
-        Roller::new(spec).total()
-    }).map_err(|_| "Invalid dicespec".into())
-}
-pub fn k_roll_dice(spec: &str) -> KetosResult<i64> {
-    roll_dice(spec).map_err(|e| (Box::new(e) as Box<::std::error::Error>).into())
-}
 pub fn score_to_mod(score: i32) -> i64 {
     match score {
         1 => -5,
@@ -111,14 +121,505 @@ pub fn score_to_mod(score: i32) -> i64 {
         _ => 0
     }
 }
+/// Whether a d20 attack roll hits, given the roll needed to beat the target's AC.
+/// A natural 20 always hits, and a natural 1 always misses.
+pub fn attack_hits(roll: i64, target: i64) -> bool {
+    roll == 20 || (roll != 1 && roll >= target)
+}
+/// Proficiency bonus for a player's level, or a monster's challenge rating
+/// (which happen to follow the same table).
+pub fn proficiency_bonus(level: i32) -> i64 {
+    2 + ((level.max(1) - 1) / 4) as i64
+}
+/// How much experience a monster of a given challenge rating is worth.
+pub fn cr_to_xp(cr: &str) -> i32 {
+    match cr.trim() {
+        "0" => 10,
+        "1/8" => 25,
+        "1/4" => 50,
+        "1/2" => 100,
+        x => match x.parse::<i32>().unwrap_or(0) {
+            1 => 200, 2 => 450, 3 => 700, 4 => 1100, 5 => 1800,
+            6 => 2300, 7 => 2900, 8 => 3900, 9 => 5000, 10 => 5900,
+            11 => 7200, 12 => 8400, 13 => 10000, 14 => 11500, 15 => 13000,
+            16 => 15000, 17 => 18000, 18 => 20000, 19 => 22000, 20 => 25000,
+            21 => 33000, 22 => 41000, 23 => 50000, 24 => 62000, 25 => 75000,
+            26 => 90000, 27 => 105000, 28 => 120000, 29 => 135000, 30 => 155000,
+            _ => 0
+        }
+    }
+}
+/// Turns an ability like "dex" or "Dexterity" into its short name.
+pub fn ability_name(name: &str) -> Option<&'static str> {
+    match &name.to_lowercase() as &_ {
+        "str" | "strength" => Some("str"),
+        "dex" | "dexterity" => Some("dex"),
+        "con" | "constitution" => Some("con"),
+        "int" | "intelligence" => Some("int"),
+        "wis" | "wisdom" => Some("wis"),
+        "cha" | "charisma" => Some("cha"),
+        _ => None
+    }
+}
+/// Finds the saving throw an SRD action calls for, from its description (e.g.
+/// "must make a DC 18 Dexterity saving throw, taking 54 (12d8) fire damage on a
+/// failed save, or half as much damage on a successful one").
+///
+/// Returns the DC, the ability, and whether a successful save halves the damage.
+pub fn save_from_description(desc: &str) -> Option<(i32, &'static str, bool)> {
+    let start = match desc.find("DC ") {
+        Some(s) => s + 3,
+        None => return None
+    };
+    let mut words = desc[start..].split_whitespace();
+    let dc = match words.next().and_then(|w| w.parse().ok()) {
+        Some(dc) => dc,
+        None => return None
+    };
+    let ability = match words.next().and_then(ability_name) {
+        Some(a) => a,
+        None => return None
+    };
+    if words.next() != Some("saving") {
+        return None;
+    }
+    Some((dc, ability, desc.contains("half as much damage")))
+}
+/// The attack (dice and damage type) an ability makes, if it does damage.
+pub fn ability_attack(abi: &Ability) -> Option<String> {
+    abi.damage_dice.as_ref().map(|dice| {
+        match abi.damage_type {
+            Some(ref t) => format!("{} {}", dice, t),
+            None => dice.clone()
+        }
+    })
+}
+/// Splits hit dice like "18d10" into the number of dice and their size.
+pub fn parse_hit_dice(hd: &str) -> Option<(i32, i32)> {
+    let mut parts = hd.trim().split('d');
+    match (parts.next().and_then(|x| x.parse().ok()), parts.next().and_then(|x| x.parse().ok())) {
+        (Some(n), Some(sides)) if n > 0 && sides > 0 => Some((n, sides)),
+        _ => None
+    }
+}
+/// Parses a challenge rating like "1/4" or "10".
+pub fn parse_cr(cr: &str) -> f64 {
+    let mut parts = cr.trim().split('/');
+    let num = parts.next().and_then(|x| x.parse::<f64>().ok()).unwrap_or(0.0);
+    match parts.next().and_then(|x| x.parse::<f64>().ok()) {
+        Some(den) if den != 0.0 => num / den,
+        _ => num
+    }
+}
+impl dice::Attributes for Player {
+    fn attribute(&self, name: &str) -> Option<i64> {
+        match name {
+            "str" => Some(score_to_mod(self.strength)),
+            "dex" => Some(score_to_mod(self.dexterity)),
+            "con" => Some(score_to_mod(self.constitution)),
+            "int" => Some(score_to_mod(self.intelligence)),
+            "wis" => Some(score_to_mod(self.wisdom)),
+            "cha" => Some(score_to_mod(self.charisma)),
+            "prof" => Some(proficiency_bonus(self.level)),
+            "level" => Some(self.level as i64),
+            _ => None
+        }
+    }
+}
+impl Monster {
+    /// The monster's saving throw bonus for an ability, if the SRD gives one.
+    pub fn save(&self, ability: &str) -> Option<i32> {
+        match ability {
+            "str" => self.strength_save,
+            "dex" => self.dexterity_save,
+            "con" => self.constitution_save,
+            "int" => self.intelligence_save,
+            "wis" => self.wisdom_save,
+            "cha" => self.charisma_save,
+            _ => None
+        }
+    }
+}
+impl dice::Attributes for Monster {
+    fn attribute(&self, name: &str) -> Option<i64> {
+        match name {
+            "str" => Some(score_to_mod(self.strength)),
+            "dex" => Some(score_to_mod(self.dexterity)),
+            "con" => Some(score_to_mod(self.constitution)),
+            "int" => Some(score_to_mod(self.intelligence)),
+            "wis" => Some(score_to_mod(self.wisdom)),
+            "cha" => Some(score_to_mod(self.charisma)),
+            "prof" => Some(proficiency_bonus(parse_cr(&self.challenge_rating) as i32)),
+            _ => None
+        }
+    }
+}
+/// Whoever's behind a combatant, for looking up `@` references in dicespecs.
+pub enum Creature {
+    Player(Player),
+    Monster(Monster),
+    Nobody
+}
+impl Creature {
+    pub fn defenses(&self) -> damage::Defenses {
+        match *self {
+            Creature::Player(ref p) => damage::Defenses {
+                vulnerabilities: &p.damage_vulnerabilities,
+                resistances: &p.damage_resistances,
+                immunities: &p.damage_immunities
+            },
+            Creature::Monster(ref m) => damage::Defenses {
+                vulnerabilities: &m.damage_vulnerabilities,
+                resistances: &m.damage_resistances,
+                immunities: &m.damage_immunities
+            },
+            Creature::Nobody => damage::Defenses::none()
+        }
+    }
+    /// The creature's saving throw bonus for an ability, and a breakdown of it.
+    pub fn save_bonus(&self, ability: &str) -> (i64, String) {
+        let md = dice::Attributes::attribute(self, ability).unwrap_or(0);
+        match *self {
+            Creature::Player(ref p) if p.save_proficiencies.iter().any(|s| s == ability) => {
+                let prof = proficiency_bonus(p.level);
+                (md + prof, format!("[{}mod {}] + [proficiency {}]", ability, md, prof))
+            },
+            Creature::Monster(ref m) if m.save(ability).is_some() => {
+                let save = m.save(ability).unwrap();
+                (save as i64, format!("[{} save {}]", ability, save))
+            },
+            Creature::Nobody => (0, "[no modifier]".into()),
+            _ => (md, format!("[{}mod {}]", ability, md))
+        }
+    }
+    pub fn condition_immunities(&self) -> &[String] {
+        match *self {
+            Creature::Monster(ref m) => &m.condition_immunities,
+            _ => &[]
+        }
+    }
+}
+impl dice::Attributes for Creature {
+    fn attribute(&self, name: &str) -> Option<i64> {
+        match *self {
+            Creature::Player(ref p) => p.attribute(name),
+            Creature::Monster(ref m) => m.attribute(name),
+            Creature::Nobody => None
+        }
+    }
+}
+/// Who a roll is made by, so it can be recorded against the right person.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Roller {
+    /// Whoever sent the command (e.g. for `,roll`).
+    Sender,
+    /// A player's character, whoever sent the command.
+    Player(i32),
+    /// A monster, or the game itself. These rolls are the DM's, and aren't
+    /// recorded against any player.
+    Nobody
+}
+impl Roller {
+    /// Whoever rolls for a combatant.
+    pub fn of(c: &Combatant) -> Roller {
+        match c.player_id {
+            Some(pid) => Roller::Player(pid),
+            None => Roller::Nobody
+        }
+    }
+}
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum RollMode {
+    Normal,
+    Advantage,
+    Disadvantage
+}
+impl RollMode {
+    pub fn parse(spec: &str) -> Result<RollMode> {
+        match &spec.to_lowercase() as &_ {
+            "adv" | "advantage" => Ok(RollMode::Advantage),
+            "dis" | "disadv" | "disadvantage" => Ok(RollMode::Disadvantage),
+            "normal" => Ok(RollMode::Normal),
+            _ => bail!("Unknown roll mode (try 'adv' or 'dis')")
+        }
+    }
+    /// Combines several sources of advantage and disadvantage. As per the
+    /// rules, any advantage and any disadvantage cancel each other out,
+    /// no matter how many of each there are.
+    pub fn resolve(modes: &[RollMode]) -> RollMode {
+        let adv = modes.contains(&RollMode::Advantage);
+        let dis = modes.contains(&RollMode::Disadvantage);
+        match (adv, dis) {
+            (true, false) => RollMode::Advantage,
+            (false, true) => RollMode::Disadvantage,
+            _ => RollMode::Normal
+        }
+    }
+    pub fn describe(&self) -> &'static str {
+        match *self {
+            RollMode::Normal => "normally",
+            RollMode::Advantage => "with advantage",
+            RollMode::Disadvantage => "with disadvantage"
+        }
+    }
+}
+pub struct D20Roll {
+    pub rolls: Vec<i64>,
+    pub chosen: usize,
+    pub mode: RollMode,
+    pub result: i64
+}
+impl D20Roll {
+    pub fn new(rolls: Vec<i64>, mode: RollMode) -> D20Roll {
+        let mut chosen = 0;
+        for (i, r) in rolls.iter().enumerate() {
+            let better = match mode {
+                RollMode::Disadvantage => *r < rolls[chosen],
+                _ => *r > rolls[chosen]
+            };
+            if better {
+                chosen = i;
+            }
+        }
+        let result = rolls[chosen];
+        D20Roll { rolls, chosen, mode, result }
+    }
+    /// Lists the rolls made, with the one that was kept in bold.
+    pub fn list(&self) -> String {
+        let rolls = self.rolls.iter()
+            .enumerate()
+            .map(|(i, r)| if i == self.chosen { format!("<b>{}</b>", r) } else { r.to_string() })
+            .collect::<Vec<_>>();
+        format!("[{}]", rolls.join(", "))
+    }
+    pub fn describe(&self) -> String {
+        format!("rolling 1d20 {}: {}", self.mode.describe(), self.list())
+    }
+}
+/// The dice rolled for an attack: the d20s, and the damage if it hit.
+pub struct AttackRoll {
+    pub d20: D20Roll,
+    pub hit: bool,
+    /// Whether the hit was critical (a natural 20, or any hit when `always_crit`).
+    pub crit: bool,
+    pub damage: Option<DiceRoll>,
+    /// The damage dice rolled again for a critical hit, if there were any.
+    pub crit_damage: Option<DiceRoll>
+}
+impl AttackRoll {
+    /// Rolls an attack that needs `target` or more on the d20 to hit, and its
+    /// damage if it does, using `roll` to roll each dicespec.
+    pub fn roll<F>(mode: RollMode, target: i64, spec: &str, always_crit: bool, mut roll: F) -> Result<AttackRoll>
+        where F: FnMut(&str) -> Result<DiceRoll> {
+        let mut rolls = vec![roll("1d20")?.total];
+        if mode != RollMode::Normal {
+            rolls.push(roll("1d20")?.total);
+        }
+        let d20 = D20Roll::new(rolls, mode);
+        let hit = attack_hits(d20.result, target);
+        let crit = hit && (d20.result == 20 || always_crit);
+        let mut ret = AttackRoll { d20, hit, crit, damage: None, crit_damage: None };
+        if hit {
+            ret.damage = Some(roll(spec)?);
+        }
+        if crit {
+            // only the dice are doubled, not any flat bonus
+            let crit_spec = dice::parse(spec)?.dice_only();
+            if crit_spec.parts.len() > 0 {
+                ret.crit_damage = Some(roll(&crit_spec.to_string())?);
+            }
+        }
+        Ok(ret)
+    }
+    /// The total damage dealt, counting any critical hit.
+    pub fn total_damage(&self) -> i64 {
+        self.damage.iter().chain(self.crit_damage.iter()).map(|r| r.total).sum()
+    }
+}
+/// Looks up whoever's behind a combatant.
+pub fn load_creature(db: &PgConnection, c: &Combatant) -> Result<Creature> {
+    if let Some(pid) = c.player_id {
+        let player = pdsl::players.filter(pdsl::id.eq(pid))
+            .get_result::<Player>(db)?;
+        Ok(Creature::Player(player))
+    }
+    else if let Some(mid) = c.monster_id {
+        let mons = mdsl::monsters.filter(mdsl::id.eq(mid))
+            .get_result::<Monster>(db)?;
+        Ok(Creature::Monster(mons))
+    }
+    else {
+        Ok(Creature::Nobody)
+    }
+}
+/// The guts of `Conn::roll_mode_for`, for use from scripts.
+pub fn buff_roll_mode(db: &PgConnection, player_id: Option<i32>, kinds: &[&str], base: RollMode) -> Result<(RollMode, Vec<String>)> {
+    let mut modes = vec![base];
+    let mut reasons = vec![];
+    if let Some(pid) = player_id {
+        let player = pdsl::players.filter(pdsl::id.eq(pid))
+            .get_result::<Player>(db)?;
+        let buffs = bdsl::buffs.filter(bdsl::name.eq_any(player.buffs))
+            .load::<Buff>(db)?;
+        for b in buffs {
+            let applies = |list: &[String]| {
+                list.iter().any(|k| k == "all" || kinds.contains(&(k as &str)))
+            };
+            if applies(&b.advantage) {
+                modes.push(RollMode::Advantage);
+                reasons.push(format!("advantage from <b>{}</b>", b.name));
+            }
+            if applies(&b.disadvantage) {
+                modes.push(RollMode::Disadvantage);
+                reasons.push(format!("disadvantage from <b>{}</b>", b.name));
+            }
+        }
+    }
+    Ok((RollMode::resolve(&modes), reasons))
+}
+/// Everything about a saving throw apart from the roll itself.
+pub struct SavingThrow {
+    pub ability: &'static str,
+    pub bonus: i64,
+    pub breakdown: String,
+    pub mode: RollMode,
+    pub reasons: Vec<String>,
+    /// The condition making the save fail automatically, if any.
+    pub auto_fail: Option<&'static str>
+}
+impl SavingThrow {
+    /// Works out a combatant's bonus, and advantage or disadvantage, for a save.
+    pub fn prepare(db: &PgConnection, comb: &Combatant, ability: &str, mode: RollMode) -> Result<SavingThrow> {
+        let ability = ability_name(ability).ok_or("Unknown ability")?;
+        let creature = load_creature(db, comb)?;
+        let (bonus, breakdown) = creature.save_bonus(ability);
+        let kind = format!("{}-save", ability);
+        let (mode, mut reasons) = buff_roll_mode(db, comb.player_id, &["save", &kind as &str], mode)?;
+        let mut modes = vec![mode];
+        for (m, r) in conditions::saves(&comb.conditions, ability) {
+            modes.push(m);
+            reasons.push(r);
+        }
+        Ok(SavingThrow {
+            ability, bonus, breakdown, reasons,
+            mode: RollMode::resolve(&modes),
+            auto_fail: conditions::fails_save(&comb.conditions, ability)
+        })
+    }
+    /// Describes the outcome of the save, given the d20 roll (if one was needed).
+    /// Also returns whether it succeeded.
+    pub fn resolve(&self, who: &str, d20: Option<&D20Roll>, dc: i64) -> (bool, String) {
+        let mut ret = format!("<b>{}</b> makes a DC {} {} saving throw!\n", who, dc, self.ability);
+        for r in self.reasons.iter() {
+            ret.push_str(&format!("<i>({})</i>\n", r));
+        }
+        let d20 = match (self.auto_fail, d20) {
+            (None, Some(d)) => d,
+            (cond, _) => {
+                ret.push_str(&format!("Being <b>{}</b>, they fail automatically.", cond.unwrap_or("unable to roll")));
+                return (false, ret);
+            }
+        };
+        if d20.mode != RollMode::Normal {
+            ret.push_str(&format!("{}\n", d20.describe()));
+        }
+        let total = d20.result + self.bonus;
+        let passed = total >= dc;
+        ret.push_str(&format!("<i>[roll {}] + {}</i> => result <b>{}</b>: {}",
+                              d20.result, self.breakdown, total,
+                              if passed { "<font color=\"green\">success</font>" } else { "<font color=\"red\">failure</font>" }));
+        (passed, ret)
+    }
+}
+/// Everything needed to roll dice and record the rolls, shared between `Conn`
+/// and the scripting functions.
+#[derive(Clone)]
+pub struct DiceTray {
+    pub rng: Rc<RefCell<DiceRng>>,
+    pub db: Rc<RefCell<PgConnection>>,
+    /// Who sent the command we're running, and where, so rolls can be recorded.
+    pub context: Rc<RefCell<Option<(String, String)>>>,
+    /// The DM's nick, which monsters' rolls are recorded under.
+    pub admin: String
+}
+impl DiceTray {
+    /// Rolls a dicespec using our random number source, logging where in the
+    /// source's sequence the roll came from (so it can be `,replay`ed later).
+    pub fn roll_spec<A: dice::Attributes>(&self, spec: &str, attrs: &A) -> Result<DiceRoll> {
+        let spec = dice::parse(spec)?;
+        let mut rng = self.rng.borrow_mut();
+        let (seed, draws) = (rng.seed(), rng.draws());
+        let roll = spec.roll(&mut *rng, attrs)?;
+        println!("[dice] seed {} draw {}: {}", seed, draws, roll);
+        Ok(roll)
+    }
+    /// Rolls a dicespec, and records the roll.
+    pub fn roll<A: dice::Attributes>(&self, spec: &str, attrs: &A, by: Roller) -> Result<DiceRoll> {
+        let (seed, draw) = {
+            let rng = self.rng.borrow();
+            (rng.seed(), rng.draws())
+        };
+        let roll = self.roll_spec(spec, attrs)?;
+        self.record(spec, &roll, seed, draw, by)?;
+        Ok(roll)
+    }
+    fn record(&self, spec: &str, roll: &DiceRoll, seed: u64, draw: u64, by: Roller) -> Result<()> {
+        let (sender, room) = match self.context.borrow().clone() {
+            Some(c) => c,
+            None => return Ok(())
+        };
+        let db = self.db.borrow();
+        let (nick, player_id) = match by {
+            Roller::Sender => {
+                let pid = pdsl::players.filter(pdsl::nick.eq(&sender as &str))
+                    .load::<Player>(&*db)?
+                    .into_iter().next().map(|p| p.id);
+                (sender, pid)
+            },
+            Roller::Player(pid) => {
+                let player = pdsl::players.filter(pdsl::id.eq(pid))
+                    .get_result::<Player>(&*db)?;
+                (player.nick, Some(pid))
+            },
+            Roller::Nobody => (self.admin.clone(), None)
+        };
+        let breakdown = roll.to_string();
+        let r = NewRoll {
+            nick: &nick,
+            room: &room,
+            player_id: player_id,
+            spec: spec,
+            result: roll.total as i32,
+            breakdown: &breakdown,
+            seed: seed as i64,
+            draw: draw as i64,
+            natural_d20: roll.natural_d20().map(|n| n as i32)
+        };
+        diesel::insert(&r).into(rodsl::rolls)
+            .execute(&*db)?;
+        Ok(())
+    }
+    /// Rolls a d20 (twice, with advantage or disadvantage), recording the rolls.
+    pub fn roll_d20(&self, mode: RollMode, by: Roller) -> Result<D20Roll> {
+        let mut rolls = vec![self.roll("1d20", &dice::NoAttributes, by)?.total];
+        if mode != RollMode::Normal {
+            rolls.push(self.roll("1d20", &dice::NoAttributes, by)?.total);
+        }
+        Ok(D20Roll::new(rolls, mode))
+    }
+}
 struct Conn {
     client: Rc<RefCell<MatrixClient>>,
     admin: String,
     db: Rc<RefCell<PgConnection>>,
     interp: Interpreter,
-    last_roll: Option<String>,
+    dice: DiceTray,
     cur_combatant: Option<i32>,
-    cur_room: Option<i32>
+    /// The current round of the encounter, or 0 if there isn't one.
+    cur_round: i32,
+    cur_room: Option<i32>,
+    /// When the current encounter began, if there is one.
+    started_at: Option<SystemTime>
 }
 
 impl Conn {
@@ -128,6 +629,24 @@ impl Conn {
         ::std::thread::sleep(::std::time::Duration::from_millis(250));
         Ok(())
     }
+    fn get_prop(&mut self, name: &str) -> Result<Option<String>> {
+        let props = prdsl::props.filter(prdsl::name.eq(name))
+            .load::<Property>(&*self.db.borrow())?;
+        Ok(props.into_iter().next().map(|p| p.value))
+    }
+    fn set_prop(&mut self, name: &str, value: &str) -> Result<()> {
+        diesel::delete(prdsl::props.filter(prdsl::name.eq(name)))
+            .execute(&*self.db.borrow())?;
+        diesel::insert(&NewProperty { name, value }).into(prdsl::props)
+            .execute(&*self.db.borrow())?;
+        Ok(())
+    }
+    /// Sends a message to the DM privately, in the room they set up with `,dmroom`.
+    fn dm_msg(&mut self, msg: &str) -> Result<()> {
+        let room = self.get_prop("dm_room")?
+            .ok_or("I don't have a private room with the DM yet. Invite me to one, and say ,dmroom there.")?;
+        self.msg(&room, msg)
+    }
     fn read_helpfile(&mut self, name: &str) -> Result<String> {
         if !name.is_alphanumeric() {
             bail!("Helpfile topic names are alphanumeric.");
@@ -176,14 +695,25 @@ impl Conn {
         self.msg(&to, "Parsing datafile...")?;
         let df: Datafile = serde_json::from_str(&contents)?;
         self.msg(&to, "Inserting data into database...")?;
-        let Datafile { mut items, rooms, mut abilities, players, monsters, weapons, buffs } = df;
+        let Datafile { mut items, rooms, mut abilities, players, mut monsters, weapons, buffs } = df;
+        // environments are matched case-insensitively by encounter/generate
+        for mons in monsters.iter_mut() {
+            mons.environments = mons.environments.iter().map(|e| e.to_lowercase()).collect();
+        }
         for wpn in weapons {
-            let Weapon { name, descrip, qty, player_id, damage_dice, attack_bonus } = wpn;
+            let Weapon { name, descrip, qty, player_id, damage_dice, attack_bonus, to_hit, damage_type } = wpn;
             abilities.push(NewAbility {
                 name: format!("Attack using {}", name),
                 descrip: format!("Weapon: {}", descrip),
                 damage_dice: damage_dice,
                 attack_bonus: attack_bonus,
+                to_hit: to_hit,
+                duration: None,
+                damage_type: damage_type,
+                healing: false,
+                save_ability: None,
+                save_dc: None,
+                half_on_save: false,
                 player_id: player_id,
                 monster_id: None,
                 uses: -1,
@@ -192,6 +722,38 @@ impl Conn {
             let room_id = None;
             items.push(NewItem { name, descrip, qty, player_id, room_id });
         }
+        let players = players.into_iter()
+            .map(|p| {
+                let DatafilePlayer { id, name, nick, typ, armor_class, hit_points, strength, intelligence,
+                                     dexterity, constitution, wisdom, charisma, initiative_bonus, level,
+                                     damage_vulnerabilities, damage_resistances, damage_immunities,
+                                     save_proficiencies, xp, cur_hp, temp_hp } = p;
+                let cur_hp = cur_hp.unwrap_or(hit_points);
+                NewPlayer { id, name, nick, typ, armor_class, hit_points, strength, intelligence,
+                            dexterity, constitution, wisdom, charisma, initiative_bonus, level,
+                            damage_vulnerabilities, damage_resistances, damage_immunities,
+                            save_proficiencies, xp, cur_hp, temp_hp }
+            })
+            .collect::<Vec<_>>();
+        for abi in abilities.iter() {
+            if let Some(ref dice) = abi.damage_dice {
+                dice::parse(dice)
+                    .chain_err(|| format!("Ability '{}' has bad damage dice", abi.name))?;
+            }
+            if let Some(ref to_hit) = abi.to_hit {
+                dice::parse(to_hit)
+                    .chain_err(|| format!("Ability '{}' has a bad to-hit bonus", abi.name))?;
+            }
+            if let Some(ref typ) = abi.damage_type {
+                damage::parse_type(typ)
+                    .chain_err(|| format!("Ability '{}' has a bad damage type", abi.name))?;
+            }
+            if let Some(ref save) = abi.save_ability {
+                if ability_name(save).is_none() || abi.save_dc.is_none() {
+                    bail!("Ability '{}' needs a save_ability like \"dex\", and a save_dc", abi.name);
+                }
+            }
+        }
         let n_items = diesel::insert(&items).into(schema::items::table)
             .execute(&*self.db.borrow())?;
         let n_rooms = diesel::insert(&rooms).into(schema::rooms::table)
@@ -219,41 +781,88 @@ impl Conn {
         self.msg(&to, "Inserting monsters into database...")?;
         let mut inserted = 0;
         for m in mons {
-            let SrdMonster { name, typ, armor_class, hit_points, strength, intelligence, dexterity,
+            let SrdMonster { name, typ, armor_class, hit_points, hit_dice, strength, intelligence, dexterity,
                              constitution, wisdom, charisma, challenge_rating, special_abilities,
-                             actions } = m;
+                             actions, damage_vulnerabilities, damage_resistances, damage_immunities,
+                             condition_immunities, strength_save, dexterity_save, constitution_save,
+                             intelligence_save, wisdom_save, charisma_save } = m;
             let room_id = vec![];
+            let damage_vulnerabilities = damage::parse_srd(&damage_vulnerabilities);
+            let damage_resistances = damage::parse_srd(&damage_resistances);
+            let damage_immunities = damage::parse_srd(&damage_immunities);
+            let condition_immunities = condition_immunities.split(',')
+                .filter_map(|c| conditions::CONDITIONS.iter().find(|x| x.name == c.trim()))
+                .map(|c| c.name.to_string())
+                .collect();
+            let multiattack = {
+                let attacks = actions.iter()
+                    .filter(|a| multiattack::is_attack(&a.desc) && a.damage_dice.is_some())
+                    .map(|a| (&a.name as &str, a.desc.contains("Melee")))
+                    .collect::<Vec<_>>();
+                match actions.iter().find(|a| a.name == "Multiattack") {
+                    Some(a) => multiattack::parse(&a.desc, &attacks),
+                    None => vec![]
+                }
+            };
             let newmons = NewMonster { name, typ, armor_class, hit_points, strength, intelligence, dexterity,
-                         constitution, wisdom, charisma, challenge_rating, room_id };
+                         constitution, wisdom, charisma, challenge_rating, room_id,
+                         damage_vulnerabilities, damage_resistances, damage_immunities,
+                         condition_immunities, strength_save, dexterity_save, constitution_save,
+                         intelligence_save, wisdom_save, charisma_save, multiattack,
+                         hit_dice, environments: vec![] };
             let newmons: Monster = diesel::insert(&newmons).into(schema::monsters::table)
                 .get_result(&*self.db.borrow())?;
             let special_abilities = special_abilities.into_iter()
                 .map(|abi| {
-                    let MonsterAbility { name, desc, damage_dice, attack_bonus } = abi;
+                    let MonsterAbility { name, desc, damage_dice, attack_bonus, .. } = abi;
                     NewAbility {
                         name: name,
+                        damage_type: None,
+                        healing: false,
+                        save_ability: None,
+                        save_dc: None,
+                        half_on_save: false,
                         descrip: desc,
                         damage_dice: damage_dice,
                         attack_bonus: attack_bonus,
                         uses_left: -1,
                         uses: -1,
                         monster_id: Some(newmons.id),
-                        player_id: None
+                        player_id: None,
+                        to_hit: None,
+                        duration: None
                     }
                 })
                 .collect::<Vec<_>>();
             let actions = actions.into_iter()
                 .map(|abi| {
-                    let MonsterAbility { name, desc, damage_dice, attack_bonus } = abi;
+                    let MonsterAbility { name, desc, damage_dice, attack_bonus, damage_bonus } = abi;
+                    let damage_type = damage::from_description(&desc).map(|t| t.to_string());
+                    let damage_dice = match (damage_dice, damage_bonus) {
+                        (Some(d), Some(b)) if b != 0 => Some(format!("{}{:+}", d, b)),
+                        (d, _) => d
+                    };
+                    // attacks that also call for a save have that as a side effect, which we leave to the DM
+                    let save = match attack_bonus {
+                        Some(ab) if ab != 0 => None,
+                        _ => save_from_description(&desc)
+                    };
                     NewAbility {
                         name: name,
+                        damage_type: damage_type,
+                        healing: false,
+                        save_ability: save.map(|(_, a, _)| a.to_string()),
+                        save_dc: save.map(|(dc, _, _)| dc),
+                        half_on_save: save.map(|(_, _, half)| half).unwrap_or(false),
                         descrip: desc,
                         damage_dice: damage_dice,
                         attack_bonus: attack_bonus,
                         uses_left: -1,
                         uses: -1,
                         monster_id: Some(newmons.id),
-                        player_id: None
+                        player_id: None,
+                        to_hit: None,
+                        duration: None
                     }
                 })
                 .collect::<Vec<_>>();
@@ -265,17 +874,146 @@ impl Conn {
         }
         Ok(inserted)
     }
-    fn roll_dice(&mut self, spec: &str) -> Result<i64> {
-        let roll = roll_dice(spec)?;
-        self.last_roll = Some(spec.into());
-        Ok(roll)
+    fn roll_dice(&mut self, spec: &str, by: Roller) -> Result<DiceRoll> {
+        self.roll_dice_as(spec, &dice::NoAttributes, by)
+    }
+    fn roll_dice_as<A: dice::Attributes>(&mut self, spec: &str, attrs: &A, by: Roller) -> Result<DiceRoll> {
+        self.dice.roll(spec, attrs, by)
+    }
+    /// Looks up the macros a player can use: their own, then the DM's global ones.
+    fn get_macros(&mut self, player_id: Option<i32>) -> Result<Vec<Macro>> {
+        let mut ret = vec![];
+        if let Some(pid) = player_id {
+            ret = madsl::macros.filter(madsl::player_id.eq(pid))
+                .order(madsl::name)
+                .load::<Macro>(&*self.db.borrow())?;
+        }
+        let globals = madsl::macros.filter(madsl::player_id.is_null())
+            .order(madsl::name)
+            .load::<Macro>(&*self.db.borrow())?;
+        ret.extend(globals);
+        Ok(ret)
+    }
+    /// If `spec` is the name of a macro the sender can use, returns the macro's
+    /// dicespec; otherwise, returns `spec` unchanged.
+    fn expand_macro(&mut self, nick: &str, spec: &str) -> Result<String> {
+        let pid = self.authenticate_nick(nick).ok().map(|p| p.id);
+        let name = spec.to_lowercase();
+        let macros = self.get_macros(pid)?;
+        Ok(match macros.into_iter().find(|m| m.name == name) {
+            Some(m) => m.spec,
+            None => spec.into()
+        })
+    }
+    /// Deletes a player's macro, or a global one if `player_id` is `None`.
+    fn delete_macro(&mut self, name: &str, player_id: Option<i32>) -> Result<usize> {
+        let name = name.to_lowercase();
+        let n = match player_id {
+            Some(pid) => diesel::delete(madsl::macros.filter(madsl::name.eq(name))
+                                        .filter(madsl::player_id.eq(pid)))
+                .execute(&*self.db.borrow())?,
+            None => diesel::delete(madsl::macros.filter(madsl::name.eq(name))
+                                   .filter(madsl::player_id.is_null()))
+                .execute(&*self.db.borrow())?
+        };
+        Ok(n)
+    }
+    fn print_macros(&mut self, macros: &[Macro]) -> String {
+        if macros.len() == 0 {
+            return "No macros.".into();
+        }
+        let mut ret = String::new();
+        for m in macros {
+            if ret != "" {
+                ret.push_str("\n");
+            }
+            let scope = if m.player_id.is_none() { " <i>(global)</i>" } else { "" };
+            ret.push_str(&format!("* <b>{}</b>: {}{}", m.name, m.spec, scope));
+        }
+        ret
+    }
+    fn describe_age(t: SystemTime) -> String {
+        let secs = SystemTime::now().duration_since(t).map(|d| d.as_secs()).unwrap_or(0);
+        match secs {
+            0...59 => "just now".into(),
+            60...3599 => format!("{} min ago", secs / 60),
+            3600...86399 => format!("{} h ago", secs / 3600),
+            _ => format!("{} days ago", secs / 86400)
+        }
+    }
+    fn print_rolls(&mut self, nick: &str, n: i64) -> Result<String> {
+        let rolls = rodsl::rolls.filter(rodsl::nick.eq(nick))
+            .order(rodsl::id.desc())
+            .limit(n)
+            .load::<Roll>(&*self.db.borrow())?;
+        if rolls.len() == 0 {
+            return Ok("No rolls yet.".into());
+        }
+        let mut ret = String::new();
+        for r in rolls.into_iter().rev() {
+            if ret != "" {
+                ret.push_str("\n");
+            }
+            ret.push_str(&format!("#{} ({}): {}", r.id, Self::describe_age(r.rolled_at), r.breakdown));
+        }
+        Ok(ret)
+    }
+    fn print_roll_stats(&mut self, player: &Player) -> Result<String> {
+        let d20s = rodsl::rolls.filter(rodsl::player_id.eq(player.id))
+            .filter(rodsl::natural_d20.is_not_null())
+            .select(rodsl::natural_d20)
+            .load::<Option<i32>>(&*self.db.borrow())?
+            .into_iter()
+            .filter_map(|r| r)
+            .collect::<Vec<_>>();
+        let total = rodsl::rolls.filter(rodsl::player_id.eq(player.id))
+            .count()
+            .get_result::<i64>(&*self.db.borrow())?;
+        if d20s.len() == 0 {
+            return Ok(format!("{} has made {} roll(s), but no d20s.", player.name, total));
+        }
+        let avg = d20s.iter().sum::<i32>() as f64 / d20s.len() as f64;
+        let nat20s = d20s.iter().filter(|&&r| r == 20).count();
+        let nat1s = d20s.iter().filter(|&&r| r == 1).count();
+        Ok(format!("<b>{}</b> has made {} roll(s), {} of them d20s.\nAverage d20: <b>{:.2}</b>\nNatural 20s: <b>{}</b>\nNatural 1s: <b>{}</b>",
+                   player.name, total, d20s.len(), avg, nat20s, nat1s))
+    }
+    /// Works out a bonus expression like `@prof+@str`, without counting it as a roll.
+    fn evaluate_bonus<A: dice::Attributes>(&mut self, spec: &str, attrs: &A) -> Result<i32> {
+        let roll = self.dice.roll_spec(spec, attrs)?;
+        Ok(roll.total as i32)
+    }
+    fn creature_for(&mut self, c: &Combatant) -> Result<Creature> {
+        load_creature(&*self.db.borrow(), c)
+    }
+    fn creature_for_nick(&mut self, nick: &str) -> Creature {
+        match self.authenticate_nick(nick) {
+            Ok(p) => Creature::Player(p),
+            Err(_) => Creature::Nobody
+        }
+    }
+    fn roll_d20(&mut self, mode: RollMode, by: Roller) -> Result<D20Roll> {
+        self.dice.roll_d20(mode, by)
+    }
+    /// Works out whether a roll should be made with advantage or disadvantage,
+    /// taking into account the buffs acting on the player (if any).
+    ///
+    /// `kinds` lists the sorts of roll being made (e.g. "attack", "check", "dex");
+    /// a buff applies if its `advantage` or `disadvantage` list mentions one of them,
+    /// or contains "all".
+    fn roll_mode_for(&mut self, player_id: Option<i32>, kinds: &[&str], base: RollMode) -> Result<(RollMode, Vec<String>)> {
+        buff_roll_mode(&*self.db.borrow(), player_id, kinds, base)
     }
     fn print_player(&mut self, p: &Player) -> String {
-        format!("#{}: <b>{}</b> the {} HP {} AC {}\nStr {} <i>({})</i> Int {} <i>({})</i> Dex {} <i>({})</i> Con {} <i>({})</i> Wis {} <i>({})</i> Cha {} <i>({})</i>{}",
+        format!("#{}: <b>{}</b> the level {} {} ({} XP) HP {}/{}{} AC {}\nStr {} <i>({})</i> Int {} <i>({})</i> Dex {} <i>({})</i> Con {} <i>({})</i> Wis {} <i>({})</i> Cha {} <i>({})</i>{}{}",
                 p.id,
                 p.name,
+                p.level,
                 p.typ,
+                p.xp,
+                p.cur_hp,
                 p.hit_points,
+                if p.temp_hp > 0 { format!(" (+{} temp)", p.temp_hp) } else { "".into() },
                 p.armor_class,
                 p.strength,
                 score_to_mod(p.strength),
@@ -294,11 +1032,31 @@ impl Conn {
                 }
                 else {
                     format!("\nActive buffs: <b>{}</b>", p.buffs.join(", "))
-                }
+                },
+                Self::print_defenses(&p.damage_vulnerabilities, &p.damage_resistances, &p.damage_immunities)
         )
     }
     fn print_monster(&mut self, m: &Monster) -> String {
-        format!("* <b>{}</b>, a {}. HP {} AC {}", m.name, m.typ, m.hit_points, m.armor_class)
+        let mut ret = format!("* <b>{}</b>, a {}. HP {}{} AC {}", m.name, m.typ, m.hit_points,
+                              m.hit_dice.as_ref().map(|hd| format!(" ({})", hd)).unwrap_or("".into()),
+                              m.armor_class);
+        ret.push_str(&Self::print_defenses(&m.damage_vulnerabilities, &m.damage_resistances, &m.damage_immunities));
+        if m.condition_immunities.len() > 0 {
+            ret.push_str(&format!(" <i>(can't be {})</i>", m.condition_immunities.join(", ")));
+        }
+        if m.multiattack.len() > 0 {
+            ret.push_str(&format!(" [multiattack: {}]", m.multiattack.join(", ")));
+        }
+        ret
+    }
+    fn print_defenses(vuln: &[String], resist: &[String], immune: &[String]) -> String {
+        let mut ret = String::new();
+        for &(what, list) in [("vulnerable to", vuln), ("resists", resist), ("immune to", immune)].iter() {
+            if list.len() > 0 {
+                ret.push_str(&format!(" <i>({} {})</i>", what, list.join(", ")));
+            }
+        }
+        ret
     }
     fn wound_descriptions(c: i32, m: i32) -> &'static str {
         let perc = ((c as f64 / m as f64) * 100.0f64) as i32;
@@ -322,6 +1080,23 @@ impl Conn {
             msg = format!("* <b>{}</b> - HP {}/{} [initiative: {}]",
                           c.name, c.cur_hp, c.max_hp, c.initiative);
         }
+        if c.temp_hp > 0 && (c.monster_id.is_none() || !short) {
+            msg.push_str(&format!(" [+{} temp HP]", c.temp_hp));
+        }
+        if c.conditions.len() > 0 {
+            msg.push_str(&format!(" <i>({})</i>", c.conditions.join(", ")));
+        }
+        if c.dead {
+            msg.push_str(" <b>[dead]</b>");
+        }
+        else if c.player_id.is_some() && c.cur_hp <= 0 {
+            if c.stable {
+                msg.push_str(" [stable]");
+            }
+            else {
+                msg.push_str(&format!(" [death saves: {} successes, {} failures]", c.death_successes, c.death_failures));
+            }
+        }
         if !short {
             msg.push_str(&format!("\nAC: {}\nHP: {}/{}\nMonster id: {:?}\nPlayer id: {:?}\nAttack dice: {}\nTo hit: {}",
                                   c.armor_class,
@@ -335,8 +1110,11 @@ impl Conn {
         msg
     }
     fn print_combatants(&mut self) -> Result<String> {
-        let r = cdsl::combatants.order(cdsl::initiative.desc()).load::<Combatant>(&*self.db.borrow())?;
+        let r = self.initiative_order()?;
         let mut msg = format!("{} combatants active:", r.len());
+        if let Some(t) = self.started_at {
+            msg = format!("Round {} of an encounter that started {}. {}", self.cur_round, Self::describe_age(t), msg);
+        }
         for c in r {
             msg.push_str("\n");
             msg.push_str(&self.print_combatant(&c, true));
@@ -355,14 +1133,24 @@ impl Conn {
         ret
     }
     fn print_ability(&mut self, a: &Ability, short: bool) -> String {
-        let dmg = if let Some(ref dice) = a.damage_dice {
-            format!(" [dmg {}]", dice)
-        } else { "".into() };
-        let atkb = if let Some(ref ab) = a.attack_bonus {
+        let dmg = match (&a.damage_dice, &a.damage_type) {
+            (&Some(ref dice), _) if a.healing => format!(" [heals {}]", dice),
+            (&Some(ref dice), &Some(ref typ)) => format!(" [dmg {} {}]", dice, typ),
+            (&Some(ref dice), &None) => format!(" [dmg {}]", dice),
+            _ => "".into()
+        };
+        let atkb = if let (&Some(ref abi), Some(dc)) = (&a.save_ability, a.save_dc) {
+            format!(" [DC {} {} save{}]", dc, abi, if a.half_on_save { ", half damage on a success" } else { "" })
+        } else if let Some(ref th) = a.to_hit {
+            format!(" [{} to hit]", th)
+        } else if let Some(ref ab) = a.attack_bonus {
             format!(" [+{} to hit]", ab)
         } else { "".into() };
+        let dur = if let Some(d) = a.duration {
+            format!(" [lasts {} rounds]", d)
+        } else { "".into() };
         let uses = if a.uses_left == -1 { "∞".into() } else { a.uses_left.to_string() };
-        let mut ret = format!("#{}: {}x <b>{}</b>{}{}", a.id, uses, a.name, dmg, atkb);
+        let mut ret = format!("#{}: {}x <b>{}</b>{}{}{}", a.id, uses, a.name, dmg, atkb, dur);
         if !short {
             for line in a.descrip.lines() {
                 ret.push_str("\n");
@@ -409,34 +1197,155 @@ impl Conn {
             .load::<Ability>(&*self.db.borrow())?;
         Ok(self.print_abilities(&results, true))
     }
-    fn monster_to_combatant(&mut self, mons: &Monster) -> Result<Combatant> {
+    /// A name for a new combatant that no other combatant has, like "Goblin 2".
+    fn unique_name(&mut self, name: &str) -> Result<String> {
+        let names = cdsl::combatants.select(cdsl::name)
+            .load::<String>(&*self.db.borrow())?;
+        let mut ret = name.to_string();
+        let mut n = 1;
+        while names.contains(&ret) {
+            n += 1;
+            ret = format!("{} {}", name, n);
+        }
+        Ok(ret)
+    }
+    /// How monsters' HP is decided: "average", "roll" or "max". This can be
+    /// set for just the current encounter, or for every encounter.
+    fn hp_mode(&mut self) -> Result<String> {
+        if let Some(m) = self.get_prop("encounter_hp_mode")? {
+            return Ok(m);
+        }
+        Ok(self.get_prop("hp_mode")?.unwrap_or("average".into()))
+    }
+    /// Works out the HP of a new combatant made from a monster, using `hp_mode`.
+    fn monster_hp(&mut self, mons: &Monster) -> Result<(i32, String)> {
+        let mode = self.hp_mode()?;
+        let (n, sides) = match mons.hit_dice.as_ref().and_then(|hd| parse_hit_dice(hd)) {
+            Some(x) if mode != "average" => x,
+            _ => return Ok((mons.hit_points, format!("{} HP (average)", mons.hit_points)))
+        };
+        let bonus = n * score_to_mod(mons.constitution) as i32;
+        let spec = format!("{}d{}{:+}", n, sides, bonus);
+        // even with a bad constitution, everything gets at least 1 HP
+        Ok(if mode == "max" {
+            let hp = ::std::cmp::max(n * sides + bonus, 1);
+            (hp, format!("{} HP (max of {})", hp, spec))
+        }
+        else {
+            let roll = self.roll_dice(&spec, Roller::Nobody)?;
+            let hp = ::std::cmp::max(roll.total as i32, 1);
+            (hp, format!("{} HP (rolled {})", hp, roll))
+        })
+    }
+    /// Makes a combatant from a monster, returning it along with a description
+    /// of how its HP was decided.
+    fn monster_to_combatant(&mut self, mons: &Monster) -> Result<(Combatant, String)> {
+        let name = self.unique_name(&mons.name)?;
+        let (hp, desc) = self.monster_hp(mons)?;
         let comb = NewCombatant {
-            name: &mons.name,
+            name: &name,
             attack: "1d1",
-            max_hp: mons.hit_points,
-            cur_hp: mons.hit_points,
+            max_hp: hp,
+            cur_hp: hp,
             armor_class: mons.armor_class,
             monster_id: Some(mons.id),
             player_id: None,
         };
-        let res = diesel::insert(&comb).into(cdsl::combatants)
+        let res: Combatant = diesel::insert(&comb).into(cdsl::combatants)
             .get_result(&*self.db.borrow())?;
-        Ok(res)
+        // every combatant gets its own uses of its monster's abilities
+        let uses = adsl::abilities.filter(adsl::monster_id.eq(mons.id))
+            .filter(adsl::uses.ne(-1))
+            .load::<Ability>(&*self.db.borrow())?
+            .into_iter()
+            .map(|a| NewCombatantUse { combatant_id: res.id, ability_id: a.id, uses_left: a.uses })
+            .collect::<Vec<_>>();
+        diesel::insert(&uses).into(cudsl::combatant_uses)
+            .execute(&*self.db.borrow())?;
+        Ok((res, desc))
     }
     fn player_to_combatant(&self, p: &Player) -> Result<Combatant> {
+        if p.dead {
+            bail!("{} is dead, and can't join an encounter.", p.name);
+        }
         let comb = NewCombatant {
             name: &p.name,
             attack: "1d1",
             max_hp: p.hit_points,
-            cur_hp: p.hit_points,
+            cur_hp: p.cur_hp,
             armor_class: p.armor_class,
             player_id: Some(p.id),
             monster_id: None
         };
-        let res = diesel::insert(&comb).into(cdsl::combatants)
+        let res: Combatant = diesel::insert(&comb).into(cdsl::combatants)
+            .get_result(&*self.db.borrow())?;
+        // players who went down in an earlier fight are still down, and pick
+        // up their death saves where they left off
+        let conditions = if p.cur_hp <= 0 { vec!["unconscious".to_string()] } else { vec![] };
+        let res = diesel::update(cdsl::combatants.filter(cdsl::id.eq(res.id)))
+            .set((cdsl::temp_hp.eq(p.temp_hp),
+                  cdsl::death_successes.eq(p.death_successes),
+                  cdsl::death_failures.eq(p.death_failures),
+                  cdsl::stable.eq(p.stable),
+                  cdsl::conditions.eq(conditions)))
             .get_result(&*self.db.borrow())?;
         Ok(res)
     }
+    /// Writes the HP and death saves of players' combatants back to the
+    /// players, so they last after the encounter.
+    fn sync_players(&mut self) -> Result<()> {
+        let combs = cdsl::combatants.filter(cdsl::player_id.is_not_null())
+            .load::<Combatant>(&*self.db.borrow())?;
+        for c in combs {
+            if let Some(pid) = c.player_id {
+                diesel::update(pdsl::players.filter(pdsl::id.eq(pid)))
+                    .set((pdsl::cur_hp.eq(::std::cmp::max(c.cur_hp, 0)),
+                          pdsl::temp_hp.eq(c.temp_hp),
+                          pdsl::death_successes.eq(c.death_successes),
+                          pdsl::death_failures.eq(c.death_failures),
+                          pdsl::stable.eq(c.stable),
+                          pdsl::dead.eq(c.dead)))
+                    .execute(&*self.db.borrow())?;
+            }
+        }
+        Ok(())
+    }
+    /// Brings every living player back to full HP, and recovers all ability uses.
+    fn long_rest(&mut self) -> Result<String> {
+        if self.cur_round > 0 {
+            bail!("You can't rest in the middle of an encounter!");
+        }
+        let n_players = diesel::update(pdsl::players.filter(pdsl::dead.eq(false)))
+            .set((pdsl::cur_hp.eq(pdsl::hit_points),
+                  pdsl::temp_hp.eq(0),
+                  pdsl::death_successes.eq(0),
+                  pdsl::death_failures.eq(0),
+                  pdsl::stable.eq(false)))
+            .execute(&*self.db.borrow())?;
+        let n_abis = self.recover_uses()?;
+        Ok(format!("{} player(s) back to full HP, and {} abilities recovered.", n_players, n_abis))
+    }
+    /// Brings a dead player back to life with some HP, as a resurrection spell would.
+    fn revive(&mut self, player: &Player, hp: i32) -> Result<String> {
+        if !player.dead {
+            bail!("{} isn't dead.", player.name);
+        }
+        if self.player_combatant(player.id)?.is_some() {
+            bail!("{} is in the encounter, so set their combatant's HP with hp= instead.", player.name);
+        }
+        if hp < 1 {
+            bail!("{} needs at least 1 HP to be alive.", player.name);
+        }
+        let hp = hp.min(player.hit_points);
+        diesel::update(pdsl::players.filter(pdsl::id.eq(player.id)))
+            .set((pdsl::cur_hp.eq(hp),
+                  pdsl::death_successes.eq(0),
+                  pdsl::death_failures.eq(0),
+                  pdsl::stable.eq(false),
+                  pdsl::dead.eq(false)))
+            .execute(&*self.db.borrow())?;
+        Ok(format!("{} is brought back to life, with {}/{} HP.", player.name, hp, player.hit_points))
+    }
     fn spell_to_player_ability(&mut self, p: &Player, s: &Spell) -> Result<Ability> {
         let abi = NewAbility {
             name: format!("Spell: {} ({})", s.name, s.typ),
@@ -449,7 +1358,14 @@ impl Conn {
             attack_bonus: None,
             uses_left: -1,
             monster_id: None,
-            player_id: Some(p.id)
+            player_id: Some(p.id),
+            to_hit: None,
+            duration: None,
+            damage_type: None,
+            healing: false,
+            save_ability: None,
+            save_dc: None,
+            half_on_save: false
         };
         let res = diesel::insert(&abi).into(adsl::abilities)
             .get_result(&*self.db.borrow())?;
@@ -508,19 +1424,21 @@ impl Conn {
             .load::<Monster>(&*self.db.borrow())?;
         Ok(mons)
     }
-    fn enter_room(&mut self, room: &Room) -> Result<String> {
-        self.end_encounter()?;
+    fn enter_room(&mut self, to: &str, room: &Room) -> Result<String> {
+        self.end_encounter(to)?;
         let mut ret = self.print_room(room)?;
         let mons = self.get_room_monsters(room)?;
         if mons.len() > 0 {
             ret += "\n* There are the following monsters in this room:";
             for m in mons {
-                let comb = self.monster_to_combatant(&m)?;
+                let (comb, desc) = self.monster_to_combatant(&m)?;
                 ret += "\n";
                 ret += &self.print_combatant(&comb, true);
+                ret += &format!(" ({})", desc);
             }
         }
         self.cur_room = Some(room.id);
+        self.save_encounter()?;
         Ok(ret)
     }
     fn authenticate_nick(&mut self, nick: &str) -> Result<Player> {
@@ -539,6 +1457,17 @@ impl Conn {
             }
         }
     }
+    /// Checks that a combatant is either the player's own, or that they're the DM.
+    fn check_own_combatant(&mut self, nick: &str, comb: &Combatant) -> Result<()> {
+        if self.check_admin(nick).is_ok() {
+            return Ok(());
+        }
+        let player = self.authenticate_nick(nick)?;
+        if comb.player_id != Some(player.id) {
+            bail!("You can only do that for your own character.");
+        }
+        Ok(())
+    }
     fn check_admin(&mut self, nick: &str) -> Result<()> {
         if nick != self.admin {
             Err("You're not the DM.".into())
@@ -571,6 +1500,14 @@ impl Conn {
             .get_result::<Combatant>(&*self.db.borrow())?;
         Ok(item)
     }
+    /// Looks up a comma-separated list of combatants.
+    fn query_combatants(&mut self, ids: &str) -> Result<Vec<Combatant>> {
+        let mut ret = vec![];
+        for id in ids.split(',') {
+            ret.push(self.query_combatant(id.trim())?);
+        }
+        Ok(ret)
+    }
     fn query_buff(&mut self, id: &str) -> Result<Buff> {
         let id = format!("%{}%", id.to_lowercase());
         let item = bdsl::buffs.filter(lower(bdsl::name).like(id))
@@ -626,162 +1563,1328 @@ impl Conn {
         }
         Ok(ret)
     }
-    fn begin_encounter(&mut self) -> Result<String> {
-        let mut ret = "Encounter!\n".to_string();
-        let players = pdsl::players.order(pdsl::id.desc())
-            .load::<Player>(&*self.db.borrow())?;
-        self.cur_combatant = None;
-        for p in players {
-            self.player_to_combatant(&p)?;
+    /// Writes the encounter state to the database, so it survives a restart.
+    fn save_encounter(&mut self) -> Result<()> {
+        let state = NewEncounterState {
+            cur_combatant: self.cur_combatant,
+            cur_round: self.cur_round,
+            room_id: self.cur_room,
+            started_at: self.started_at
+        };
+        let db = self.db.borrow();
+        // if the insert fails, keep the state we saved last time
+        db.transaction(|| -> Result<()> {
+            diesel::delete(esdsl::encounter_state)
+                .execute(&*db)?;
+            diesel::insert(&state).into(esdsl::encounter_state)
+                .execute(&*db)?;
+            Ok(())
+        })
+    }
+    /// Picks up the encounter state from where `save_encounter` left it.
+    fn restore_encounter(&mut self) -> Result<()> {
+        let state = esdsl::encounter_state.load::<EncounterState>(&*self.db.borrow())?;
+        if let Some(state) = state.into_iter().next() {
+            self.cur_combatant = state.cur_combatant;
+            self.cur_round = state.cur_round;
+            self.cur_room = state.room_id;
+            self.started_at = state.started_at;
         }
-        ret += &self.roll_initiative()?;
-        ret += "\n\n";
-        ret += &self.print_combatants()?;
-        let first = cdsl::combatants.order(cdsl::initiative.desc())
-            .limit(1)
-            .get_result::<Combatant>(&*self.db.borrow())?;
-        ret += &format!("\n\nIt's now {}'s turn.\n", first.name);
-        self.cur_combatant = Some(first.id);
-        ret += &self.describe_turn_options()?;
-        Ok(ret)
+        Ok(())
     }
-    fn end_encounter(&mut self) -> Result<String> {
-        diesel::delete(cdsl::combatants)
-            .execute(&*self.db.borrow())?;
-        self.cur_combatant = None;
-        Ok("Encounter ended.".to_string())
+    fn snapshot(&mut self) -> Result<combatlog::Snapshot> {
+        let combatants = cdsl::combatants.load::<Combatant>(&*self.db.borrow())?;
+        let effects = edsl::effects.load::<Effect>(&*self.db.borrow())?;
+        let ability_uses = adsl::abilities.filter(adsl::uses_left.ne(-1))
+            .load::<Ability>(&*self.db.borrow())?
+            .into_iter()
+            .map(|a| (a.id, a.uses_left))
+            .collect();
+        let combatant_uses = cudsl::combatant_uses.load::<CombatantUse>(&*self.db.borrow())?
+            .into_iter()
+            .map(|u| (u.id, u.uses_left))
+            .collect();
+        Ok(combatlog::Snapshot {
+            combatants, effects, ability_uses, combatant_uses,
+            cur_combatant: self.cur_combatant,
+            cur_round: self.cur_round
+        })
     }
-    fn advance_turn(&mut self) -> Result<String> {
-        let cc = self.cur_combatant.ok_or("It's nobody's turn!".to_string())?;
-        let res = cdsl::combatants.order(cdsl::initiative.desc())
-            .load::<Combatant>(&*self.db.borrow())?;
-        let mut ret = None;
-        let mut last = -1i32;
-        for (i, c) in res.into_iter().enumerate() {
-            if i == 0 {
-                last = c.id;
-                ret = Some(c);
-            }
-            else if last == cc {
-                ret = Some(c);
-                break;
-            }
-            else {
-                last = c.id;
-            }
+    /// Records what a command changed since the `before` snapshot was taken.
+    fn log_action(&mut self, nick: &str, command: &str, before: &combatlog::Snapshot) -> Result<()> {
+        if before.cur_round > 0 && self.cur_round == 0 {
+            // the command ended the encounter, and the log with it
+            return Ok(());
         }
-        let ret = ret.ok_or("Something terrible has happened!".to_string())?;
-        self.cur_combatant = Some(ret.id);
-        Ok(format!("It's now {}'s turn.\n{}", ret.name, self.describe_turn_options()?))
+        let after = self.snapshot()?;
+        let changes = combatlog::diff(before, &after);
+        if changes.len() == 0 {
+            return Ok(());
+        }
+        let action = diesel::insert(&NewCombatAction { nick, command }).into(cadsl::combat_actions)
+            .get_result::<CombatAction>(&*self.db.borrow())?;
+        let changes = changes.iter()
+            .map(|c| NewCombatChange {
+                action_id: action.id,
+                kind: c.kind,
+                target_id: c.target_id,
+                field: c.field,
+                old_value: &c.old_value,
+                new_value: &c.new_value
+            })
+            .collect::<Vec<_>>();
+        diesel::insert(&changes).into(ccdsl::combat_changes)
+            .execute(&*self.db.borrow())?;
+        Ok(())
     }
-    fn load_buff(&mut self, name: &str) -> Result<()> {
-        let buff = self.query_buff(name)?;
-        self.interp.run_single_expr(&buff.code, None).map_err(|e| self.interp.format_error(&e))?;
+    fn clear_log(&mut self) -> Result<()> {
+        diesel::delete(ccdsl::combat_changes)
+            .execute(&*self.db.borrow())?;
+        diesel::delete(cadsl::combat_actions)
+            .execute(&*self.db.borrow())?;
         Ok(())
     }
-    fn roll_initiative(&mut self) -> Result<String> {
-        let res = cdsl::combatants.load::<Combatant>(&*self.db.borrow())?;
-        let mut ret = "Rolling initiative...\n".to_string();
-        for c in res {
-            let initiative = if let Some(pid) = c.player_id {
-                let player = pdsl::players.filter(pdsl::id.eq(pid))
-                    .get_result::<Player>(&*self.db.borrow())?;
-                let roll = self.roll_dice("1d20")?;
-                let result = roll + score_to_mod(player.dexterity) + (player.initiative_bonus as i64);
-                ret.push_str(&format!("\n{} (player): [roll {}] + [dexmod {}] + [itvmod {}] => [initiative {}]",
-                                      player.name,
-                                      roll,
-                                      score_to_mod(player.dexterity),
-                                      player.initiative_bonus,
-                                      result));
-                result
-            }
-            else if let Some(mid) = c.monster_id {
+    /// Puts a logged field back to its old value, once `check_revertible` has
+    /// made sure it's still there.
+    fn revert_change(&mut self, c: &CombatChange) -> Result<()> {
+        let v = &c.old_value as &str;
+        match (&c.kind as &str, c.target_id) {
+            ("combatant", Some(id)) => {
+                let comb = cdsl::combatants.filter(cdsl::id.eq(id));
+                let db = self.db.borrow();
+                match &c.field as &str {
+                    "cur_hp" => diesel::update(comb).set(cdsl::cur_hp.eq(v.parse::<i32>()?)).execute(&*db)?,
+                    "temp_hp" => diesel::update(comb).set(cdsl::temp_hp.eq(v.parse::<i32>()?)).execute(&*db)?,
+                    "initiative" => diesel::update(comb).set(cdsl::initiative.eq(v.parse::<i32>()?)).execute(&*db)?,
+                    "init_dex" => diesel::update(comb).set(cdsl::init_dex.eq(v.parse::<i32>()?)).execute(&*db)?,
+                    "init_tiebreak" => diesel::update(comb).set(cdsl::init_tiebreak.eq(v.parse::<i32>()?)).execute(&*db)?,
+                    "attack" => diesel::update(comb).set(cdsl::attack.eq(v)).execute(&*db)?,
+                    "attack_bonus" => diesel::update(comb).set(cdsl::attack_bonus.eq(v.parse::<i32>()?)).execute(&*db)?,
+                    "conditions" => {
+                        let conds = v.split(',').filter(|x| x.len() > 0).map(|x| x.to_string()).collect::<Vec<_>>();
+                        diesel::update(comb).set(cdsl::conditions.eq(conds)).execute(&*db)?
+                    },
+                    "death_successes" => diesel::update(comb).set(cdsl::death_successes.eq(v.parse::<i32>()?)).execute(&*db)?,
+                    "death_failures" => diesel::update(comb).set(cdsl::death_failures.eq(v.parse::<i32>()?)).execute(&*db)?,
+                    "stable" => diesel::update(comb).set(cdsl::stable.eq(v == "true")).execute(&*db)?,
+                    "dead" => diesel::update(comb).set(cdsl::dead.eq(v == "true")).execute(&*db)?,
+                    f => bail!("Can't undo a change to a combatant's {}.", f)
+                };
+            },
+            ("effect", Some(id)) => {
+                diesel::update(edsl::effects.filter(edsl::id.eq(id)))
+                    .set(edsl::rounds_left.eq(v.parse::<i32>()?))
+                    .execute(&*self.db.borrow())?;
+            },
+            ("ability", Some(id)) => {
+                diesel::update(adsl::abilities.filter(adsl::id.eq(id)))
+                    .set(adsl::uses_left.eq(v.parse::<i32>()?))
+                    .execute(&*self.db.borrow())?;
+            },
+            ("combatant_use", Some(id)) => {
+                diesel::update(cudsl::combatant_uses.filter(cudsl::id.eq(id)))
+                    .set(cudsl::uses_left.eq(v.parse::<i32>()?))
+                    .execute(&*self.db.borrow())?;
+            },
+            ("encounter", None) => {
+                match &c.field as &str {
+                    "cur_combatant" => self.cur_combatant = v.parse().ok(),
+                    "cur_round" => self.cur_round = v.parse()?,
+                    f => bail!("Can't undo a change to the encounter's {}.", f)
+                }
+                self.save_encounter()?;
+            },
+            _ => bail!("Can't undo a change to a {}.", c.kind)
+        }
+        Ok(())
+    }
+    /// Checks that whatever a logged change was made to is still there to be
+    /// changed back.
+    fn check_revertible(&mut self, c: &CombatChange) -> Result<()> {
+        let db = self.db.borrow();
+        let (what, id) = match (&c.kind as &str, c.target_id) {
+            ("combatant", Some(id)) => ("Combatant", id),
+            ("effect", Some(id)) => ("Effect", id),
+            ("ability", Some(id)) => ("Ability", id),
+            ("combatant_use", Some(id)) => ("Use", id),
+            ("encounter", None) if c.field == "cur_combatant" => match c.old_value.parse::<i32>() {
+                Ok(id) => ("Combatant", id),
+                Err(_) => return Ok(())
+            },
+            _ => return Ok(())
+        };
+        let count = match what {
+            "Combatant" => cdsl::combatants.filter(cdsl::id.eq(id)).count().get_result::<i64>(&*db)?,
+            "Effect" => edsl::effects.filter(edsl::id.eq(id)).count().get_result::<i64>(&*db)?,
+            "Use" => cudsl::combatant_uses.filter(cudsl::id.eq(id)).count().get_result::<i64>(&*db)?,
+            _ => adsl::abilities.filter(adsl::id.eq(id)).count().get_result::<i64>(&*db)?
+        };
+        if count == 0 {
+            bail!("{} #{} isn't around any more, so that can't be undone.", what, id);
+        }
+        Ok(())
+    }
+    /// Reverts the last `n` logged actions, most recent first.
+    ///
+    /// Nothing is changed unless all of them can be undone.
+    fn undo(&mut self, n: i64) -> Result<String> {
+        if n < 1 {
+            bail!("Undo at least one action.");
+        }
+        let actions = cadsl::combat_actions.order(cadsl::id.desc())
+            .limit(n)
+            .load::<CombatAction>(&*self.db.borrow())?;
+        if actions.len() == 0 {
+            bail!("There's nothing to undo.");
+        }
+        let mut logged = vec![];
+        for a in actions {
+            let changes = ccdsl::combat_changes.filter(ccdsl::action_id.eq(a.id))
+                .order(ccdsl::id.desc())
+                .load::<CombatChange>(&*self.db.borrow())?;
+            for c in changes.iter() {
+                self.check_revertible(c)?;
+            }
+            logged.push((a, changes));
+        }
+        let (cur_combatant, cur_round) = (self.cur_combatant, self.cur_round);
+        let db = self.db.clone();
+        let db = db.borrow();
+        // if anything goes wrong halfway, put everything back how it was
+        let res = db.transaction(|| -> Result<String> {
+            let mut ret = String::new();
+            for (a, changes) in logged {
+                for c in changes.iter() {
+                    self.revert_change(c)?;
+                }
+                diesel::delete(ccdsl::combat_changes.filter(ccdsl::action_id.eq(a.id)))
+                    .execute(&*self.db.borrow())?;
+                diesel::delete(cadsl::combat_actions.filter(cadsl::id.eq(a.id)))
+                    .execute(&*self.db.borrow())?;
+                ret.push_str(&format!("Undid #{} ({}: <b>{}</b>), changing {} thing(s) back.\n", a.id, a.nick, a.command, changes.len()));
+            }
+            Ok(ret)
+        });
+        if res.is_err() {
+            self.cur_combatant = cur_combatant;
+            self.cur_round = cur_round;
+        }
+        let mut ret = res?;
+        ret.push_str(&self.print_combatants()?);
+        Ok(ret)
+    }
+    fn print_log(&mut self, n: i64) -> Result<String> {
+        let actions = cadsl::combat_actions.order(cadsl::id.desc())
+            .limit(n)
+            .load::<CombatAction>(&*self.db.borrow())?;
+        if actions.len() == 0 {
+            return Ok("The combat log is empty.".into());
+        }
+        let combs = cdsl::combatants.load::<Combatant>(&*self.db.borrow())?;
+        let mut ret = "Combat log (most recent first):".to_string();
+        for a in actions {
+            ret.push_str(&format!("\n#{} ({}) {}: <b>{}</b>", a.id, Self::describe_age(a.logged_at), a.nick, a.command));
+            let changes = ccdsl::combat_changes.filter(ccdsl::action_id.eq(a.id))
+                .order(ccdsl::id.asc())
+                .load::<CombatChange>(&*self.db.borrow())?;
+            for c in changes {
+                let what = match (&c.kind as &str, c.target_id) {
+                    ("combatant", Some(id)) => combs.iter().find(|x| x.id == id)
+                        .map(|x| x.name.clone())
+                        .unwrap_or(format!("combatant #{}", id)),
+                    ("encounter", _) => "encounter".into(),
+                    (kind, Some(id)) => format!("{} #{}", kind, id),
+                    (kind, None) => kind.into()
+                };
+                ret.push_str(&format!("\n  - {}'s {}: {} => {}", what, c.field, c.old_value, c.new_value));
+            }
+        }
+        Ok(ret)
+    }
+    fn begin_encounter(&mut self) -> Result<String> {
+        let mut ret = "Encounter!\n".to_string();
+        let players = pdsl::players.filter(pdsl::dead.eq(false))
+            .order(pdsl::id.desc())
+            .load::<Player>(&*self.db.borrow())?;
+        self.cur_combatant = None;
+        for p in players {
+            self.player_to_combatant(&p)?;
+        }
+        ret += &self.roll_initiative()?;
+        ret += "\n\n";
+        ret += &self.print_combatants()?;
+        let first = self.initiative_order()?.into_iter().next()
+            .ok_or("There's nobody to have an encounter with!")?;
+        self.cur_round = 1;
+        ret += &format!("\n\n<b>Round 1!</b>\nIt's now {}'s turn.\n", first.name);
+        self.cur_combatant = Some(first.id);
+        self.started_at = Some(SystemTime::now());
+        self.save_encounter()?;
+        self.clear_log()?;
+        ret += &self.describe_turn_options()?;
+        Ok(ret)
+    }
+    fn end_encounter(&mut self, to: &str) -> Result<String> {
+        let mut ret = String::new();
+        let effects = edsl::effects.load::<Effect>(&*self.db.borrow())?;
+        for e in effects {
+            ret += &self.expire_effect(to, &e)?;
+        }
+        if self.cur_round > 0 {
+            ret += &self.award_xp()?;
+            // only once a fight's over, so that entering a room (which ends
+            // the last encounter) still spawns its monsters with this mode
+            diesel::delete(prdsl::props.filter(prdsl::name.eq("encounter_hp_mode")))
+                .execute(&*self.db.borrow())?;
+        }
+        self.sync_players()?;
+        // nothing from this encounter can be undone once its combatants are gone
+        self.clear_log()?;
+        diesel::delete(cudsl::combatant_uses)
+            .execute(&*self.db.borrow())?;
+        diesel::delete(cdsl::combatants)
+            .execute(&*self.db.borrow())?;
+        self.cur_combatant = None;
+        self.cur_round = 0;
+        self.started_at = None;
+        self.save_encounter()?;
+        ret += "Encounter ended.";
+        Ok(ret)
+    }
+    /// The players in the current encounter (or all of them, if there isn't one).
+    fn party(&mut self) -> Result<Vec<Player>> {
+        let combs = cdsl::combatants.filter(cdsl::player_id.is_not_null())
+            .load::<Combatant>(&*self.db.borrow())?;
+        let players = pdsl::players.load::<Player>(&*self.db.borrow())?;
+        let party = players.into_iter()
+            .filter(|p| combs.len() == 0 || combs.iter().any(|c| c.player_id == Some(p.id)))
+            .collect::<Vec<_>>();
+        if party.len() == 0 {
+            bail!("There aren't any players to face them.");
+        }
+        Ok(party)
+    }
+    /// Picks a mix of monsters that makes an encounter of some difficulty
+    /// for the party, optionally only of one type or from one environment.
+    fn pick_monsters(&mut self, rating: &str, typ: Option<&str>, env: Option<&str>) -> Result<Vec<Monster>> {
+        let levels = self.party()?.iter().map(|p| p.level).collect::<Vec<_>>();
+        let thresholds = difficulty::rate(&[], &levels).thresholds;
+        let range = difficulty::xp_range(&thresholds, rating)
+            .ok_or("The difficulty should be easy, medium, hard or deadly.")?;
+        let mut query = mdsl::monsters.into_boxed();
+        if let Some(t) = typ {
+            query = query.filter(lower(mdsl::typ).like(format!("%{}%", t.to_lowercase())));
+        }
+        if let Some(e) = env {
+            query = query.filter(mdsl::environments.contains(vec![e.to_lowercase()]));
+        }
+        let monsters = query.load::<Monster>(&*self.db.borrow())?;
+        if let (0, Some(e)) = (monsters.len(), env) {
+            bail!("No monsters live in '{}'. Monsters from the SRD don't list environments, so only ones loaded with loaddata can be found by one.", e);
+        }
+        let candidates = monsters.into_iter()
+            .filter(|m| {
+                let xp = cr_to_xp(&m.challenge_rating);
+                xp > 0 && difficulty::group_size(xp, levels.len(), range.1) > 0
+            })
+            .collect::<Vec<_>>();
+        if candidates.len() == 0 {
+            bail!("There aren't any monsters like that which would make a {} encounter.", rating);
+        }
+        let xps = candidates.iter().map(|m| cr_to_xp(&m.challenge_rating)).collect::<Vec<_>>();
+        // these draws aren't anyone's roll, so they're neither logged nor recorded
+        let rng = self.dice.rng.clone();
+        let picked = difficulty::fill_budget(&xps, levels.len(), range, |n| rng.borrow_mut().gen_range(0, n));
+        Ok(picked.into_iter().map(|i| candidates[i].clone()).collect())
+    }
+    /// Rates an encounter against some monsters, for the players in the current
+    /// encounter (or all of them, if there isn't one).
+    fn rate_encounter(&mut self, monsters: &[Monster]) -> Result<String> {
+        let party = self.party()?;
+        let xp = monsters.iter().map(|m| cr_to_xp(&m.challenge_rating)).collect::<Vec<_>>();
+        let levels = party.iter().map(|p| p.level).collect::<Vec<_>>();
+        let d = difficulty::rate(&xp, &levels);
+        let mut ret = format!("{} monster(s) against {} player(s):", monsters.len(), party.len());
+        for (m, xp) in monsters.iter().zip(xp.iter()) {
+            ret.push_str(&format!("\n* {} (CR {}): {} XP", m.name, m.challenge_rating, xp));
+        }
+        ret.push_str(&format!("\nTotal: {} XP, x{} for the number of monsters = <b>{} XP</b>", d.total_xp, d.multiplier, d.adjusted_xp));
+        let thresholds = difficulty::RATINGS.iter().zip(d.thresholds.iter())
+            .map(|(r, t)| format!("{} {}", r, t))
+            .collect::<Vec<_>>();
+        ret.push_str(&format!("\nParty thresholds: {}", thresholds.join(", ")));
+        ret.push_str(&format!("\nThis encounter is <b>{}</b>.", d.rating));
+        Ok(ret)
+    }
+    /// Splits the experience for the monsters defeated in this encounter between
+    /// all the players who took part in it.
+    fn award_xp(&mut self) -> Result<String> {
+        let combs = cdsl::combatants.load::<Combatant>(&*self.db.borrow())?;
+        let mut xp = 0;
+        for c in combs.iter().filter(|c| c.dead) {
+            if let Some(mid) = c.monster_id {
                 let mons = mdsl::monsters.filter(mdsl::id.eq(mid))
                     .get_result::<Monster>(&*self.db.borrow())?;
-                let roll = self.roll_dice("1d20")?;
-                let result = roll + score_to_mod(mons.dexterity);
-                ret.push_str(&format!("\n{} (monster): [roll {}] + [dexmod {}] => [initiative {}]",
-                                      mons.name,
-                                      roll,
-                                      score_to_mod(mons.dexterity),
-                                      result));
-                result
+                xp += cr_to_xp(&mons.challenge_rating);
+            }
+        }
+        // everyone who took part gets a share, even if they didn't make it
+        let players = combs.iter()
+            .filter_map(|c| c.player_id)
+            .collect::<Vec<_>>();
+        if xp == 0 || players.len() == 0 {
+            return Ok(String::new());
+        }
+        let each = xp / players.len() as i32;
+        diesel::update(pdsl::players.filter(pdsl::id.eq_any(players)))
+            .set(pdsl::xp.eq(pdsl::xp + each))
+            .execute(&*self.db.borrow())?;
+        Ok(format!("{} XP earned: <b>{}</b> XP each.\n", xp, each))
+    }
+    /// Ends the encounter if either the players or everyone else have all been
+    /// taken out. Players who are still making death saves haven't been taken
+    /// out yet, so the fight goes on until they're stable or dead.
+    fn check_victory(&mut self, to: &str) -> Result<String> {
+        if self.cur_round == 0 {
+            return Ok(String::new());
+        }
+        let combs = cdsl::combatants.load::<Combatant>(&*self.db.borrow())?;
+        let (players, foes): (Vec<_>, Vec<_>) = combs.into_iter().partition(|c| c.player_id.is_some());
+        let ret = if foes.len() > 0 && foes.iter().all(|c| c.dead) {
+            "\n\n<b>Victory!</b> All foes have been defeated.\n"
+        }
+        else if players.len() > 0 && players.iter().all(|c| c.dead || (c.cur_hp <= 0 && c.stable)) {
+            "\n\n<b>Defeat!</b> The whole party has fallen.\n"
+        }
+        else {
+            return Ok(String::new());
+        };
+        Ok(format!("{}{}", ret, self.end_encounter(to)?))
+    }
+    /// Works out who goes after `cc`, and whether we've gone round to the start again.
+    fn next_combatant(&mut self, cc: i32) -> Result<(Combatant, bool)> {
+        let res = self.initiative_order()?;
+        let mut ret = None;
+        let mut last = -1i32;
+        let mut wrapped = true;
+        for (i, c) in res.into_iter().enumerate() {
+            if i == 0 {
+                last = c.id;
+                ret = Some(c);
+            }
+            else if last == cc {
+                ret = Some(c);
+                wrapped = false;
+                break;
+            }
+            else {
+                last = c.id;
+            }
+        }
+        let ret = ret.ok_or("Something terrible has happened!".to_string())?;
+        Ok((ret, wrapped))
+    }
+    fn advance_turn(&mut self, to: &str) -> Result<String> {
+        let mut cc = self.cur_combatant.ok_or("It's nobody's turn!".to_string())?;
+        let n_combatants = cdsl::combatants.count()
+            .get_result::<i64>(&*self.db.borrow())?;
+        let mut st = String::new();
+        // skip over anyone who can't act, but don't go round forever if nobody can
+        for _ in 0..n_combatants {
+            let (next, wrapped) = self.next_combatant(cc)?;
+            if wrapped {
+                self.cur_round += 1;
+                st += &format!("<b>Round {}!</b>\n", self.cur_round);
+            }
+            self.cur_combatant = Some(next.id);
+            cc = next.id;
+            st += &self.tick_effects(to, &next)?;
+            let next = self.get_current_combatant()?;
+            if next.dead {
+                if next.player_id.is_some() {
+                    st += &format!("{} is dead.\n", next.name);
+                }
+                continue;
+            }
+            if next.player_id.is_some() && next.cur_hp <= 0 && !next.stable {
+                st += &self.death_save(&next)?;
+            }
+            let next = self.get_current_combatant()?;
+            match conditions::incapacitated(&next.conditions) {
+                Some(c) => st += &format!("{} is {}, and loses their turn.\n", next.name, c),
+                None => {
+                    st += &format!("It's now {}'s turn.\n{}", next.name, self.describe_turn_options()?);
+                    self.save_encounter()?;
+                    return Ok(st);
+                }
+            }
+        }
+        self.save_encounter()?;
+        st += "Nobody is able to act!";
+        Ok(st)
+    }
+    /// Rolls a death saving throw for a player at 0 HP.
+    fn death_save(&mut self, comb: &Combatant) -> Result<String> {
+        let (mode, reasons) = self.roll_mode_for(comb.player_id, &["save", "death-save"], RollMode::Normal)?;
+        let d20 = self.roll_d20(mode, Roller::of(comb))?;
+        let mut ret = format!("{} makes a death saving throw", comb.name);
+        for r in reasons {
+            ret.push_str(&format!(" <i>({})</i>", r));
+        }
+        ret.push_str(&format!(": {}\n", d20.list()));
+        let (mut successes, mut failures) = (comb.death_successes, comb.death_failures);
+        match d20.result {
+            20 => {
+                let (_, st) = self.set_hp(comb, 1)?;
+                ret.push_str(&format!("Natural 20! {}", st));
+                return Ok(ret);
+            },
+            1 => {
+                failures += 2;
+                ret.push_str("Natural 1: two failures.\n");
+            },
+            x if x >= 10 => successes += 1,
+            _ => failures += 1
+        }
+        let dead = failures >= 3;
+        let stable = !dead && successes >= 3;
+        diesel::update(cdsl::combatants.filter(cdsl::id.eq(comb.id)))
+            .set((cdsl::death_successes.eq(successes),
+                  cdsl::death_failures.eq(failures),
+                  cdsl::stable.eq(stable),
+                  cdsl::dead.eq(dead)))
+            .execute(&*self.db.borrow())?;
+        if dead {
+            ret.push_str(&format!("<b>{} has died.</b>\n", comb.name));
+        }
+        else if stable {
+            ret.push_str(&format!("{} is now stable.\n", comb.name));
+        }
+        else {
+            ret.push_str(&format!("{} successes, {} failures.\n", successes, failures));
+        }
+        Ok(ret)
+    }
+    /// Sets a combatant's HP. Players dropping to 0 fall unconscious, and
+    /// players brought up from 0 come round again.
+    fn set_hp(&mut self, comb: &Combatant, hp: i32) -> Result<(Combatant, String)> {
+        if comb.player_id.is_none() {
+            let res = diesel::update(cdsl::combatants.filter(cdsl::id.eq(comb.id)))
+                .set((cdsl::cur_hp.eq(hp), cdsl::dead.eq(hp <= 0)))
+                .get_result::<Combatant>(&*self.db.borrow())?;
+            let ret = if res.dead && !comb.dead {
+                format!("<b>{} is defeated!</b>\n", comb.name)
+            } else { String::new() };
+            return Ok((res, ret));
+        }
+        let hp = hp.max(0);
+        let mut ret = String::new();
+        let down = comb.cur_hp <= 0 || comb.dead;
+        let mut res = diesel::update(cdsl::combatants.filter(cdsl::id.eq(comb.id)))
+            .set((cdsl::cur_hp.eq(hp),
+                  cdsl::death_successes.eq(0),
+                  cdsl::death_failures.eq(0),
+                  cdsl::stable.eq(false),
+                  cdsl::dead.eq(false)))
+            .get_result::<Combatant>(&*self.db.borrow())?;
+        let unconscious = res.conditions.iter().any(|c| c == "unconscious");
+        if hp == 0 && !unconscious {
+            res = self.add_condition(&res, "unconscious")?;
+            ret.push_str(&format!("{} falls unconscious!\n", comb.name));
+        }
+        else if hp > 0 && down {
+            if unconscious {
+                res = self.remove_condition(&res, "unconscious")?;
+            }
+            if comb.dead {
+                ret.push_str(&format!("{} is brought back to life!\n", comb.name));
             }
             else {
-                let roll = self.roll_dice("1d20")?;
-                ret.push_str(&format!("\n{} (???): [roll {}] => [initiative {}]",
-                                      c.name,
-                                      roll,
-                                      roll));
-                roll
+                ret.push_str(&format!("{} regains consciousness!\n", comb.name));
+            }
+        }
+        Ok((res, ret))
+    }
+    /// Deals damage to a combatant. Players go down at 0 HP rather than below it,
+    /// and taking damage while down counts as a failed death save (two, for a
+    /// critical hit).
+    fn apply_damage(&mut self, comb: &Combatant, dmg: i64, crit: bool) -> Result<(Combatant, String)> {
+        let mut dmg = dmg as i32;
+        if comb.dead {
+            return Ok((comb.clone(), format!("{} is already dead.\n", comb.name)));
+        }
+        let mut absorbed = String::new();
+        if comb.temp_hp > 0 && dmg > 0 {
+            let soaked = dmg.min(comb.temp_hp);
+            dmg -= soaked;
+            diesel::update(cdsl::combatants.filter(cdsl::id.eq(comb.id)))
+                .set(cdsl::temp_hp.eq(comb.temp_hp - soaked))
+                .execute(&*self.db.borrow())?;
+            absorbed = format!("{}'s temporary HP absorbs {} damage.\n", comb.name, soaked);
+            if dmg == 0 {
+                let comb = cdsl::combatants.filter(cdsl::id.eq(comb.id))
+                    .get_result::<Combatant>(&*self.db.borrow())?;
+                return Ok((comb, absorbed));
+            }
+        }
+        let (res, st) = self.apply_hp_damage(comb, dmg, crit)?;
+        Ok((res, absorbed + &st))
+    }
+    /// Has a player heal someone with one of their healing abilities, picking
+    /// the ability by its dice.
+    fn heal_with_ability(&mut self, player: &Player, tgt: &Combatant, spec: &str) -> Result<String> {
+        let spec = dice::parse(spec)?;
+        let abis = adsl::abilities.filter(adsl::player_id.eq(player.id))
+            .filter(adsl::healing.eq(true))
+            .load::<Ability>(&*self.db.borrow())?
+            .into_iter()
+            .filter(|a| a.damage_dice.as_ref().and_then(|d| dice::parse(d).ok()) == Some(spec.clone()))
+            .collect::<Vec<_>>();
+        if abis.len() == 0 {
+            bail!("{} doesn't have a healing ability that heals {}.", player.name, spec);
+        }
+        let abi = abis.into_iter().find(|a| a.uses_left != 0)
+            .ok_or("That ability has no uses left!")?;
+        let dice = abi.damage_dice.clone().unwrap();
+        let roll = self.roll_dice_as(&dice, player, Roller::Player(player.id))?;
+        let (_, st) = self.heal(tgt, roll.total as i32)?;
+        if abi.uses_left != -1 {
+            diesel::update(adsl::abilities.filter(adsl::id.eq(abi.id)))
+                .set(adsl::uses_left.eq(abi.uses_left - 1))
+                .execute(&*self.db.borrow())?;
+        }
+        Ok(format!("{} uses {} on {}!\nHealing: {}\n{}", player.name, abi.name, tgt.name, roll, st))
+    }
+    /// Gives a combatant temporary HP. These don't stack, so the higher amount is kept.
+    fn give_temp_hp(&mut self, comb: &Combatant, amount: i32) -> Result<(Combatant, String)> {
+        if amount <= comb.temp_hp {
+            return Ok((comb.clone(), format!("{} already has {} temporary HP, so nothing changes.\n", comb.name, comb.temp_hp)));
+        }
+        let res = diesel::update(cdsl::combatants.filter(cdsl::id.eq(comb.id)))
+            .set(cdsl::temp_hp.eq(amount))
+            .get_result::<Combatant>(&*self.db.borrow())?;
+        Ok((res, format!("{} gains {} temporary HP.\n", comb.name, amount)))
+    }
+    /// Heals a combatant, up to their maximum HP.
+    fn heal(&mut self, comb: &Combatant, amount: i32) -> Result<(Combatant, String)> {
+        if comb.dead {
+            bail!("{} is dead, and can't be healed.", comb.name);
+        }
+        let from = comb.cur_hp.max(0);
+        let hp = (from + amount.max(0)).min(comb.max_hp).max(from);
+        let (res, st) = self.set_hp(comb, hp)?;
+        Ok((res, format!("{} regains {} HP, and is now at {}/{}.\n{}", comb.name, hp - from, hp, comb.max_hp, st)))
+    }
+    fn apply_hp_damage(&mut self, comb: &Combatant, dmg: i32, crit: bool) -> Result<(Combatant, String)> {
+        // damage that was all resisted shouldn't count against a dying player
+        if dmg <= 0 {
+            return Ok((comb.clone(), format!("{} takes no damage.\n", comb.name)));
+        }
+        if comb.player_id.is_none() {
+            return self.set_hp(comb, comb.cur_hp - dmg);
+        }
+        if comb.cur_hp > 0 {
+            let left = comb.cur_hp - dmg;
+            if left > 0 || -left < comb.max_hp {
+                return self.set_hp(comb, left);
+            }
+        }
+        else if dmg < comb.max_hp {
+            let failures = comb.death_failures + if crit { 2 } else { 1 };
+            let res = diesel::update(cdsl::combatants.filter(cdsl::id.eq(comb.id)))
+                .set((cdsl::death_failures.eq(failures),
+                      cdsl::stable.eq(false),
+                      cdsl::dead.eq(failures >= 3)))
+                .get_result::<Combatant>(&*self.db.borrow())?;
+            let ret = if res.dead {
+                format!("<b>{} has died.</b>\n", comb.name)
+            }
+            else {
+                format!("Taking damage while down, {} suffers {} death save failure(s).\n", comb.name, failures - comb.death_failures)
             };
-            diesel::update(cdsl::combatants.filter(cdsl::id.eq(c.id)))
-                .set(cdsl::initiative.eq(initiative as i32))
+            return Ok((res, ret));
+        }
+        let (res, _) = self.set_hp(comb, 0)?;
+        let res = diesel::update(cdsl::combatants.filter(cdsl::id.eq(res.id)))
+            .set(cdsl::dead.eq(true))
+            .get_result::<Combatant>(&*self.db.borrow())?;
+        Ok((res, format!("<b>{} is killed outright by massive damage!</b>\n", comb.name)))
+    }
+    /// Puts a timed effect on a combatant, lasting until the start of `source`'s
+    /// turn `rounds` rounds from now.
+    fn add_effect(&mut self, name: &str, kind: &str, on: &Combatant, source: &Combatant, rounds: i32) -> Result<Effect> {
+        if self.cur_round == 0 {
+            bail!("Effects can only be timed during an encounter.");
+        }
+        if rounds < 1 {
+            bail!("An effect has to last at least one round.");
+        }
+        let eff = NewEffect {
+            name: name,
+            kind: kind,
+            combatant_id: on.id,
+            source_id: source.id,
+            rounds_left: rounds
+        };
+        let res = diesel::insert(&eff).into(edsl::effects)
+            .get_result(&*self.db.borrow())?;
+        Ok(res)
+    }
+    /// Removes an effect, taking off the buff that goes with it if there is one.
+    fn expire_effect(&mut self, to: &str, e: &Effect) -> Result<String> {
+        diesel::delete(edsl::effects.filter(edsl::id.eq(e.id)))
+            .execute(&*self.db.borrow())?;
+        let comb = cdsl::combatants.filter(cdsl::id.eq(e.combatant_id))
+            .load::<Combatant>(&*self.db.borrow())?;
+        let comb = match comb.into_iter().next() {
+            Some(c) => c,
+            None => return Ok(String::new())
+        };
+        if e.kind == "condition" {
+            self.remove_condition(&comb, &e.name)?;
+        }
+        if e.kind == "buff" {
+            if let Some(pid) = comb.player_id {
+                let player = pdsl::players.filter(pdsl::id.eq(pid))
+                    .get_result::<Player>(&*self.db.borrow())?;
+                if player.buffs.contains(&e.name) {
+                    self.remove_buff(to, player, &e.name)?;
+                }
+            }
+        }
+        Ok(format!("{}'s <b>{}</b> has worn off.\n", comb.name, e.name))
+    }
+    /// Counts down the effects that run out at the start of `source`'s turn.
+    fn tick_effects(&mut self, to: &str, source: &Combatant) -> Result<String> {
+        let effects = edsl::effects.filter(edsl::source_id.eq(source.id))
+            .load::<Effect>(&*self.db.borrow())?;
+        let mut ret = String::new();
+        for e in effects {
+            if e.rounds_left <= 1 {
+                ret += &self.expire_effect(to, &e)?;
+            }
+            else {
+                diesel::update(edsl::effects.filter(edsl::id.eq(e.id)))
+                    .set(edsl::rounds_left.eq(e.rounds_left - 1))
+                    .execute(&*self.db.borrow())?;
+            }
+        }
+        Ok(ret)
+    }
+    fn print_effects(&mut self) -> Result<String> {
+        let effects = edsl::effects.order(edsl::id)
+            .load::<Effect>(&*self.db.borrow())?;
+        if effects.len() == 0 {
+            return Ok("No effects are active.".into());
+        }
+        let mut ret = format!("Round {}. Active effects:", self.cur_round);
+        for e in effects {
+            let on = cdsl::combatants.filter(cdsl::id.eq(e.combatant_id))
+                .get_result::<Combatant>(&*self.db.borrow())?;
+            let source = cdsl::combatants.filter(cdsl::id.eq(e.source_id))
+                .get_result::<Combatant>(&*self.db.borrow())?;
+            ret += &format!("\n#{}: <b>{}</b> ({}) on {}, {} round(s) left, ending at the start of {}'s turn",
+                            e.id, e.name, e.kind, on.name, e.rounds_left, source.name);
+        }
+        Ok(ret)
+    }
+    fn add_condition(&mut self, comb: &Combatant, name: &str) -> Result<Combatant> {
+        let cond = conditions::find(name)?;
+        if self.creature_for(comb)?.condition_immunities().iter().any(|c| c == cond.name) {
+            bail!("{} is immune to being {}.", comb.name, cond.name);
+        }
+        if comb.conditions.iter().any(|c| c == cond.name) {
+            bail!("{} is already {}.", comb.name, cond.name);
+        }
+        let mut conds = comb.conditions.clone();
+        conds.push(cond.name.into());
+        let res = diesel::update(cdsl::combatants.filter(cdsl::id.eq(comb.id)))
+            .set(cdsl::conditions.eq(conds))
+            .get_result(&*self.db.borrow())?;
+        Ok(res)
+    }
+    fn remove_condition(&mut self, comb: &Combatant, name: &str) -> Result<Combatant> {
+        let cond = conditions::find(name)?;
+        let mut conds = comb.conditions.clone();
+        conds.retain(|c| c != cond.name);
+        let res = diesel::update(cdsl::combatants.filter(cdsl::id.eq(comb.id)))
+            .set(cdsl::conditions.eq(conds))
+            .get_result(&*self.db.borrow())?;
+        diesel::delete(edsl::effects.filter(edsl::combatant_id.eq(comb.id))
+                       .filter(edsl::kind.eq("condition"))
+                       .filter(edsl::name.eq(cond.name)))
+            .execute(&*self.db.borrow())?;
+        Ok(res)
+    }
+    fn player_combatant(&mut self, player_id: i32) -> Result<Option<Combatant>> {
+        let combs = cdsl::combatants.filter(cdsl::player_id.eq(player_id))
+            .load::<Combatant>(&*self.db.borrow())?;
+        Ok(combs.into_iter().next())
+    }
+    fn load_buff(&mut self, buff: &Buff) -> Result<()> {
+        self.interp.run_single_expr(&buff.code, None).map_err(|e| self.interp.format_error(&e))?;
+        Ok(())
+    }
+    fn add_buff(&mut self, to: &str, mut player: Player, name: &str) -> Result<Buff> {
+        let buff = self.query_buff(name)?;
+        self.load_buff(&buff)?;
+        self.interp.call("buff", vec![
+            to.into(),
+            player.id.into()
+        ]).map_err(|e| self.interp.format_error(&e))?;
+        player.buffs.push(buff.name.clone());
+        diesel::update(pdsl::players.filter(pdsl::id.eq(player.id)))
+            .set(pdsl::buffs.eq(player.buffs))
+            .execute(&*self.db.borrow())?;
+        Ok(buff)
+    }
+    fn remove_buff(&mut self, to: &str, mut player: Player, name: &str) -> Result<Buff> {
+        let buff = self.query_buff(name)?;
+        if !player.buffs.contains(&buff.name) {
+            bail!("No such buff is acting on that player at this time.");
+        }
+        self.load_buff(&buff)?;
+        self.interp.call("debuff", vec![
+            to.into(),
+            player.id.into()
+        ]).map_err(|e| self.interp.format_error(&e))?;
+        player.buffs.retain(|b| b != &buff.name);
+        diesel::update(pdsl::players.filter(pdsl::id.eq(player.id)))
+            .set(pdsl::buffs.eq(player.buffs))
+            .execute(&*self.db.borrow())?;
+        if let Some(comb) = self.player_combatant(player.id)? {
+            diesel::delete(edsl::effects.filter(edsl::combatant_id.eq(comb.id))
+                           .filter(edsl::kind.eq("buff"))
+                           .filter(edsl::name.eq(&buff.name)))
                 .execute(&*self.db.borrow())?;
         }
+        Ok(buff)
+    }
+    /// Everyone in the encounter, in the order they take their turns.
+    fn initiative_order(&mut self) -> Result<Vec<Combatant>> {
+        Ok(cdsl::combatants.order((cdsl::initiative.desc(), cdsl::init_dex.desc(), cdsl::init_tiebreak.desc(), cdsl::id.asc()))
+           .load::<Combatant>(&*self.db.borrow())?)
+    }
+    /// Rolls initiative for one combatant, returning the result, their dexterity score
+    /// (for breaking ties) and a description of the roll.
+    fn initiative_roll(&mut self, c: &Combatant) -> Result<(i64, i32, String)> {
+        if let Some(pid) = c.player_id {
+            let player = pdsl::players.filter(pdsl::id.eq(pid))
+                .get_result::<Player>(&*self.db.borrow())?;
+            let (mode, _) = self.roll_mode_for(Some(pid), &["initiative"], RollMode::Normal)?;
+            let roll = self.roll_d20(mode, Roller::Player(pid))?;
+            let result = roll.result + score_to_mod(player.dexterity) + (player.initiative_bonus as i64);
+            let st = format!("{} (player): [{}] + [dexmod {}] + [itvmod {}] => [initiative {}]",
+                             player.name,
+                             roll.describe(),
+                             score_to_mod(player.dexterity),
+                             player.initiative_bonus,
+                             result);
+            Ok((result, player.dexterity, st))
+        }
+        else if let Some(mid) = c.monster_id {
+            let mons = mdsl::monsters.filter(mdsl::id.eq(mid))
+                .get_result::<Monster>(&*self.db.borrow())?;
+            let roll = self.roll_dice("1d20", Roller::Nobody)?.total;
+            let result = roll + score_to_mod(mons.dexterity);
+            let st = format!("{} (monster): [roll {}] + [dexmod {}] => [initiative {}]",
+                             mons.name,
+                             roll,
+                             score_to_mod(mons.dexterity),
+                             result);
+            Ok((result, mons.dexterity, st))
+        }
+        else {
+            let roll = self.roll_dice("1d20", Roller::Nobody)?.total;
+            let st = format!("{} (???): [roll {}] => [initiative {}]",
+                             c.name,
+                             roll,
+                             roll);
+            Ok((roll, 10, st))
+        }
+    }
+    /// Rolls (or rerolls) initiative for one combatant, clearing any old roll-off.
+    fn set_initiative(&mut self, c: &Combatant) -> Result<String> {
+        let (initiative, dex, st) = self.initiative_roll(c)?;
+        diesel::update(cdsl::combatants.filter(cdsl::id.eq(c.id)))
+            .set((cdsl::initiative.eq(initiative as i32),
+                  cdsl::init_dex.eq(dex),
+                  cdsl::init_tiebreak.eq(0)))
+            .execute(&*self.db.borrow())?;
+        Ok(st)
+    }
+    fn roll_initiative(&mut self) -> Result<String> {
+        let res = cdsl::combatants.load::<Combatant>(&*self.db.borrow())?;
+        let mut ret = "Rolling initiative...\n".to_string();
+        for c in res {
+            ret.push_str("\n");
+            ret.push_str(&self.set_initiative(&c)?);
+        }
+        ret.push_str(&self.break_ties()?);
         Ok(ret)
     }
+    /// Breaks initiative ties: first by dexterity score, then by a roll-off
+    /// between those who are still tied, which goes on until nobody's tied.
+    /// Roll-offs are kept, so the order stays the same between turns; only
+    /// those without one (or who rolled the same as someone else) roll.
+    fn break_ties(&mut self) -> Result<String> {
+        let order = self.initiative_order()?;
+        let mut ret = String::new();
+        let mut i = 0;
+        while i < order.len() {
+            let tied = order[i..].iter()
+                .take_while(|c| c.initiative == order[i].initiative && c.init_dex == order[i].init_dex)
+                .collect::<Vec<_>>();
+            i += tied.len();
+            let mut values = tied.iter().map(|c| c.init_tiebreak).collect::<Vec<_>>();
+            // whoever hasn't rolled off yet, or rolled the same as someone else
+            let still_tied = |values: &[i32]| (0..values.len())
+                .filter(|&j| values[j] == 0 || values.iter().filter(|&&v| v == values[j]).count() > 1)
+                .collect::<Vec<_>>();
+            if tied.len() < 2 || still_tied(&values[..]).len() == 0 {
+                continue;
+            }
+            // a d20 can't separate more than 20 people
+            let die = format!("1d{}", ::std::cmp::max(20, tied.len()));
+            let mut rounds = vec![];
+            loop {
+                let rerolls = still_tied(&values[..]);
+                if rerolls.len() == 0 {
+                    break;
+                }
+                for &j in rerolls.iter() {
+                    values[j] = self.roll_dice(&die, Roller::of(tied[j]))?.total as i32;
+                }
+                // the first round shows everyone, and later ones just the rerolls
+                let shown = if rounds.len() == 0 { (0..tied.len()).collect() } else { rerolls };
+                let rolls = shown.iter()
+                    .map(|&j| format!("{} {}", tied[j].name, values[j]))
+                    .collect::<Vec<_>>();
+                rounds.push(rolls.join(", "));
+            }
+            for (c, &v) in tied.iter().zip(values.iter()) {
+                if v != c.init_tiebreak {
+                    diesel::update(cdsl::combatants.filter(cdsl::id.eq(c.id)))
+                        .set(cdsl::init_tiebreak.eq(v))
+                        .execute(&*self.db.borrow())?;
+                }
+            }
+            ret.push_str(&format!("\nTie on initiative {} (and dexterity {}), broken by a roll-off: {}",
+                                  tied[0].initiative, tied[0].init_dex, rounds.join("; still tied, so rerolling: ")));
+        }
+        Ok(ret)
+    }
+    /// Says where a combatant comes in the initiative order.
+    fn describe_slot(&mut self, c: &Combatant) -> Result<String> {
+        let order = self.initiative_order()?;
+        let pos = order.iter().position(|x| x.id == c.id)
+            .ok_or("That combatant isn't in the initiative order.")?;
+        Ok(if pos == 0 {
+            format!("{} goes first in the initiative order.", c.name)
+        }
+        else {
+            format!("{} goes after {} in the initiative order.", c.name, order[pos - 1].name)
+        })
+    }
+    /// How many uses of an ability a combatant has left, or -1 if it can be
+    /// used as often as they like. Monsters' uses are counted for each
+    /// combatant, so two goblins don't share one pool.
+    fn uses_left(&mut self, comb: &Combatant, abi: &Ability) -> Result<i32> {
+        if comb.monster_id.is_none() || abi.uses == -1 {
+            return Ok(abi.uses_left);
+        }
+        let uses = cudsl::combatant_uses.filter(cudsl::combatant_id.eq(comb.id))
+            .filter(cudsl::ability_id.eq(abi.id))
+            .load::<CombatantUse>(&*self.db.borrow())?;
+        Ok(uses.into_iter().next().map(|u| u.uses_left).unwrap_or(abi.uses))
+    }
+    /// Uses up one of a combatant's uses of an ability, failing if they've
+    /// none left.
+    fn spend_use(&mut self, comb: &Combatant, abi: &Ability) -> Result<()> {
+        let left = self.uses_left(comb, abi)?;
+        if left == -1 {
+            return Ok(());
+        }
+        if left == 0 {
+            bail!("That ability has no uses left!");
+        }
+        if comb.monster_id.is_none() {
+            diesel::update(adsl::abilities.filter(adsl::id.eq(abi.id)))
+                .set(adsl::uses_left.eq(left - 1))
+                .execute(&*self.db.borrow())?;
+            return Ok(());
+        }
+        let n = diesel::update(cudsl::combatant_uses.filter(cudsl::combatant_id.eq(comb.id))
+                               .filter(cudsl::ability_id.eq(abi.id)))
+            .set(cudsl::uses_left.eq(left - 1))
+            .execute(&*self.db.borrow())?;
+        if n == 0 {
+            let new = NewCombatantUse { combatant_id: comb.id, ability_id: abi.id, uses_left: left - 1 };
+            diesel::insert(&new).into(cudsl::combatant_uses)
+                .execute(&*self.db.borrow())?;
+        }
+        Ok(())
+    }
     fn recover_uses(&mut self) -> Result<usize> {
         let changed = diesel::update(adsl::abilities)
             .set(adsl::uses_left.eq(adsl::uses))
             .execute(&*self.db.borrow())?;
         Ok(changed)
     }
-    fn check(&mut self, player: &Player, axiom: &str) -> Result<String> {
-        let axiom = match &axiom.to_lowercase() as &_ {
-            "str" | "strength" => player.strength,
-            "dex" | "dexterity" => player.dexterity,
-            "wis" | "wisdom" => player.wisdom,
-            "int" | "intelligence" => player.intelligence,
-            "cha" | "charisma" => player.charisma,
-            "con" | "constitution" => player.constitution,
+    /// Makes an ability check for a player, recording the roll against `by`
+    /// (the DM, for secret checks).
+    fn check(&mut self, player: &Player, axiom: &str, mode: RollMode, by: Roller) -> Result<String> {
+        let (kind, axiom) = match &axiom.to_lowercase() as &_ {
+            "str" | "strength" => ("str", player.strength),
+            "dex" | "dexterity" => ("dex", player.dexterity),
+            "wis" | "wisdom" => ("wis", player.wisdom),
+            "int" | "intelligence" => ("int", player.intelligence),
+            "cha" | "charisma" => ("cha", player.charisma),
+            "con" | "constitution" => ("con", player.constitution),
             _ => bail!("Unknown player attribute")
         };
         let md = score_to_mod(axiom);
-        let roll = self.roll_dice("1d20")?;
+        let (mode, mut reasons) = self.roll_mode_for(Some(player.id), &["check", kind], mode)?;
+        let mut modes = vec![mode];
+        if let Some(comb) = self.player_combatant(player.id)? {
+            for (m, r) in conditions::checks(&comb.conditions) {
+                modes.push(m);
+                reasons.push(r);
+            }
+        }
+        let mode = RollMode::resolve(&modes);
+        let d20 = self.roll_d20(mode, by)?;
+        let roll = d20.result;
+        let mut ret = String::new();
+        for r in reasons {
+            ret.push_str(&format!("<i>({})</i>\n", r));
+        }
+        if mode != RollMode::Normal {
+            ret.push_str(&format!("{}\n", d20.describe()));
+        }
         if roll == 1 {
-            Ok("CRITICAL FAILURE! (rolled a natural 1)".into())
+            ret.push_str("CRITICAL FAILURE! (rolled a natural 1)");
         }
         else if roll == 20 {
-            Ok("GREAT SUCCESS. (rolled a natural 20)".into())
+            ret.push_str("GREAT SUCCESS. (rolled a natural 20)");
         }
         else {
-            Ok(format!("<i>[roll {}] + [modifier {}]</i> => result <b>{}</b>", roll, md, roll + md))
+            ret.push_str(&format!("<i>[roll {}] + [modifier {}]</i> => result <b>{}</b>", roll, md, roll + md));
         }
+        Ok(ret)
     }
-    fn attack(&mut self, from: &Combatant, to: &Combatant) -> Result<String> {
+    fn saving_throw(&mut self, comb: &Combatant, ability: &str, dc: i64, mode: RollMode) -> Result<(bool, String)> {
+        let save = SavingThrow::prepare(&*self.db.borrow(), comb, ability, mode)?;
+        let d20 = match save.auto_fail {
+            Some(_) => None,
+            None => Some(self.roll_d20(save.mode, Roller::of(comb))?)
+        };
+        Ok(save.resolve(&comb.name, d20.as_ref(), dc))
+    }
+    /// Works out whether `from` attacks `to` with advantage or disadvantage.
+    fn attack_mode(&mut self, from: &Combatant, to: &Combatant, mode: RollMode) -> Result<(RollMode, Vec<String>)> {
+        let (mode, mut reasons) = self.roll_mode_for(from.player_id, &["attack"], mode)?;
+        let (against, against_reasons) = self.roll_mode_for(to.player_id, &["attacked"], RollMode::Normal)?;
+        reasons.extend(against_reasons);
+        let mut modes = vec![mode, against];
+        for (m, r) in conditions::attacking(&from.conditions).into_iter()
+            .chain(conditions::attacked(&to.conditions)) {
+            modes.push(m);
+            reasons.push(r);
+        }
+        Ok((RollMode::resolve(&modes), reasons))
+    }
+    fn print_odds(&mut self, spec: &str, dist: &odds::Distribution, mode: RollMode) -> String {
+        let mut ret = format!("Odds for <b>{}</b>", spec);
+        if mode != RollMode::Normal {
+            ret.push_str(&format!(" (rolled {})", mode.describe()));
+        }
+        ret.push_str(&format!(":\nmin {}, max {}, mean <b>{:.2}</b>", dist.min(), dist.max(), dist.mean()));
+        if dist.probs.len() > 25 {
+            ret.push_str(&format!("\n({} possible results; too many to list)", dist.probs.len()));
+        }
+        else {
+            for (&x, &p) in dist.probs.iter() {
+                ret.push_str(&format!("\n{}: {:.2}% <i>(at least {}: {:.2}%)</i>", x, p * 100.0, x, dist.prob_at_least(x) * 100.0));
+            }
+        }
+        ret
+    }
+    /// Works out the chance of `from` hitting `to`, using the same rules as `attack`.
+    fn hit_odds(&mut self, from: &Combatant, to: &Combatant, mode: RollMode) -> Result<String> {
+        let mut ret = format!("<b>{}</b> [to-hit: {}] attacking <b>{}</b> [AC: {}]:\n",
+                              from.name,
+                              from.attack_bonus,
+                              to.name,
+                              to.armor_class);
+        let (mode, reasons) = self.attack_mode(from, to, mode)?;
+        for r in reasons {
+            ret.push_str(&format!("<i>({})</i>\n", r));
+        }
+        let target = (to.armor_class - from.attack_bonus) as i64;
+        let d20 = odds::distribution(&dice::parse("1d20")?, &dice::NoAttributes)?
+            .with_mode(mode);
+        let hit = d20.prob_where(|r| attack_hits(r, target));
+        let crit = match conditions::crit_against(&to.conditions) {
+            Some(c) => {
+                ret.push_str(&format!("<i>(every hit is critical against a <b>{}</b> target)</i>\n", c));
+                hit
+            },
+            None => d20.prob(20)
+        };
+        ret.push_str(&format!("Rolling 1d20 {}, needing <b>{}</b>: chance to hit <b>{:.1}%</b> (critical hit {:.1}%)\n",
+                              mode.describe(), target, hit * 100.0, crit * 100.0));
+        let attrs = self.creature_for(from)?;
+        let (spec, typ) = damage::split_attack(&from.attack)?;
+        let spec = dice::parse(spec)?;
+        let mut dmg = odds::distribution(&spec, &attrs)?;
+        // a critical hit rolls the dice again, but not any flat bonus
+        let crit_dice = spec.dice_only();
+        let mut crit_dmg = if crit_dice.parts.len() > 0 {
+            Some(odds::distribution(&crit_dice, &attrs)?)
+        } else { None };
+        if let Some(typ) = typ {
+            let defender = self.creature_for(to)?;
+            let (_, reasons) = defender.defenses().apply(1, &typ);
+            for r in reasons {
+                ret.push_str(&format!("<i>({} is {})</i>\n", to.name, r));
+            }
+            dmg = dmg.map(|x| defender.defenses().apply(x, &typ).0);
+            crit_dmg = crit_dmg.map(|d| d.map(|x| defender.defenses().apply(x, &typ).0));
+        }
+        let crit_mean = crit_dmg.map(|d| d.mean()).unwrap_or(0.0);
+        ret.push_str(&format!("Damage {}: mean {:.2} (min {}, max {})\n", from.attack, dmg.mean(), dmg.min(), dmg.max()));
+        ret.push_str(&format!("Expected damage per attack: <b>{:.2}</b>", hit * dmg.mean() + crit * crit_mean));
+        Ok(ret)
+    }
+    /// Adjusts damage for a combatant's resistances (and so on), saying why it changed.
+    fn apply_defenses(&mut self, to: &Combatant, dmg: i64, typ: &damage::DamageType) -> Result<(i64, String)> {
+        let defender = self.creature_for(to)?;
+        let (new_dmg, reasons) = defender.defenses().apply(dmg, typ);
+        let mut ret = String::new();
+        if reasons.len() > 0 {
+            for r in reasons {
+                ret.push_str(&format!("<i>({} is {})</i>\n", to.name, r));
+            }
+            ret.push_str(&format!("New damage = <b>{}</b>\n", new_dmg));
+        }
+        Ok((new_dmg, ret))
+    }
+    fn attack(&mut self, from: &Combatant, to: &Combatant, mode: RollMode) -> Result<String> {
+        if let Some(c) = conditions::incapacitated(&from.conditions) {
+            bail!("{} is {}, and can't attack.", from.name, c);
+        }
+        if to.dead && to.player_id.is_none() {
+            bail!("{} has already been defeated.", to.name);
+        }
         let mut ret = String::new();
         ret.push_str(&format!("<b>{}</b> [to-hit: {}] attacks <b>{}</b> [AC: {}]!\n",
                               from.name,
                               from.attack_bonus,
                               to.name,
                               to.armor_class));
+        let (mode, reasons) = self.attack_mode(from, to, mode)?;
+        for r in reasons {
+            ret.push_str(&format!("<i>({})</i>\n", r));
+        }
         let target = to.armor_class - from.attack_bonus;
         ret.push_str(&format!("Checking AC: dice roll required = <b>{}</b>\n", target));
-        let roll = self.roll_dice("1d20")?;
-        ret.push_str(&format!("rolling 1d20: result = <b>{}</b>\n\n", roll));
-        if (roll < target as i64 && roll != 20) || roll == 1 {
-            if roll == 1 {
-                ret.push_str(&format!("CRITICAL FAILURE!"));
+        let attrs = self.creature_for(from)?;
+        let (spec, typ) = damage::split_attack(&from.attack)?;
+        let crit_against = conditions::crit_against(&to.conditions);
+        let by = Roller::of(from);
+        let atk = AttackRoll::roll(mode, target as i64, spec, crit_against.is_some(),
+                                   |s| self.roll_dice_as(s, &attrs, by))?;
+        let roll = atk.d20.result;
+        ret.push_str(&format!("{}: result = <b>{}</b>\n\n", atk.d20.describe(), roll));
+        let dmg_roll = match atk.damage {
+            Some(ref d) => d,
+            None => {
+                if roll == 1 {
+                    ret.push_str(&format!("CRITICAL FAILURE!"));
+                }
+                else {
+                    ret.push_str(&format!("Attack failed."));
+                }
+                return Ok(ret);
             }
-            else {
-                ret.push_str(&format!("Attack failed."));
+        };
+        match typ {
+            Some(ref t) => ret.push_str(&format!("Dealing {} damage: {}\n", t, dmg_roll)),
+            None => ret.push_str(&format!("Dealing damage: {}\n", dmg_roll))
+        }
+        let mut dmg = atk.total_damage();
+        if atk.crit {
+            if let Some(c) = crit_against {
+                ret.push_str(&format!("<i>(every hit is critical against a <b>{}</b> target)</i>\n", c));
+            }
+            if let Some(ref crit_roll) = atk.crit_damage {
+                ret.push_str(&format!("Critical hit! Dealing extra damage: {}\nNew damage = <b>{}</b>\n", crit_roll, dmg));
             }
-            return Ok(ret);
         }
-        let mut dmg = self.roll_dice(&from.attack)?;
-        ret.push_str(&format!("Dealing {} damage: result = <b>{}</b>\n", from.attack, dmg));
-        if roll == 20 {
-            dmg += self.roll_dice(&from.attack)?;
-            ret.push_str(&format!("Critical hit! Dealing extra damage. New damage = <b>{}</b>\n", dmg));
+        if let Some(ref t) = typ {
+            let (new_dmg, st) = self.apply_defenses(to, dmg, t)?;
+            dmg = new_dmg;
+            ret.push_str(&st);
         }
-        let to = diesel::update(cdsl::combatants.filter(cdsl::id.eq(to.id)))
-            .set(cdsl::cur_hp.eq(to.cur_hp - dmg as i32))
-            .get_result::<Combatant>(&*self.db.borrow())?;
+        let (to, st) = self.apply_damage(to, dmg, atk.crit)?;
+        ret.push_str(&st);
         ret.push_str(&format!("Opponent's state after attack:\n\n{}", self.print_combatant(&to, true)));
         Ok(ret)
     }
+    /// A copy of `from` with its attack and to-hit bonus set to those of an ability.
+    fn ability_attacker(&mut self, from: &Combatant, abi: &Ability) -> Result<Combatant> {
+        let mut from = from.clone();
+        if let Some(attack) = ability_attack(abi) {
+            from.attack = attack;
+        }
+        if let Some(ref th) = abi.to_hit {
+            let attrs = self.creature_for(&from)?;
+            from.attack_bonus = self.evaluate_bonus(th, &attrs)?;
+        }
+        else if let Some(ab) = abi.attack_bonus {
+            from.attack_bonus = ab;
+        }
+        Ok(from)
+    }
+    /// Uses an ability on some targets: healing them, attacking each of them, or
+    /// making them all save against it (with one damage roll for everyone, as
+    /// with a dragon's breath).
+    fn use_on(&mut self, user: &Combatant, abi: &Ability, targets: &[Combatant], mode: RollMode) -> Result<String> {
+        if self.uses_left(user, abi)? == 0 {
+            bail!("That ability has no uses left!");
+        }
+        if let Some(c) = conditions::incapacitated(&user.conditions) {
+            bail!("{} is {}, and can't do anything.", user.name, c);
+        }
+        if let Some(t) = targets.iter().find(|t| t.dead && t.player_id.is_none()) {
+            bail!("{} has already been defeated.", t.name);
+        }
+        // make sure the ability works on every target before anyone's hurt by it
+        if abi.healing {
+            abi.damage_dice.as_ref().ok_or("That ability doesn't say how much it heals.")?;
+        }
+        else if let Some(ref ability) = abi.save_ability {
+            abi.save_dc.ok_or("That ability doesn't have a save DC.")?;
+            if let Some(attack) = ability_attack(abi) {
+                damage::split_attack(&attack)?;
+            }
+            for t in targets {
+                SavingThrow::prepare(&*self.db.borrow(), t, ability, RollMode::Normal)?;
+            }
+        }
+        else if abi.damage_dice.is_some() {
+            let from = self.ability_attacker(user, abi)?;
+            damage::split_attack(&from.attack)?;
+        }
+        else {
+            bail!("{} doesn't attack, heal, or call for a saving throw, so it can't be used on anyone.", abi.name);
+        }
+        let db = self.db.clone();
+        let db = db.borrow();
+        // and if something still goes wrong halfway, nobody takes any of it
+        db.transaction(|| -> Result<String> {
+            self.spend_use(user, abi)?;
+            let names = targets.iter().map(|t| &t.name as &str).collect::<Vec<_>>();
+            let mut ret = format!("<b>{}</b> uses <b>{}</b> on {}!\n\n", user.name, abi.name, names.join(", "));
+            let attrs = self.creature_for(user)?;
+            if abi.healing {
+                let dice = abi.damage_dice.clone().unwrap();
+                for t in targets {
+                    let roll = self.roll_dice_as(&dice, &attrs, Roller::of(user))?;
+                    let (_, st) = self.heal(t, roll.total as i32)?;
+                    ret.push_str(&format!("Healing {}: {}\n{}\n", t.name, roll, st));
+                }
+            }
+            else if let Some(ref ability) = abi.save_ability {
+                let dc = abi.save_dc.unwrap();
+                let dmg = match ability_attack(abi) {
+                    Some(attack) => {
+                        let (spec, typ) = damage::split_attack(&attack)?;
+                        let roll = self.roll_dice_as(spec, &attrs, Roller::of(user))?;
+                        match typ {
+                            Some(ref t) => ret.push_str(&format!("Rolling {} damage: {}\n\n", t, roll)),
+                            None => ret.push_str(&format!("Rolling damage: {}\n\n", roll))
+                        }
+                        Some((roll.total, typ))
+                    },
+                    None => None
+                };
+                for t in targets {
+                    let (passed, st) = self.saving_throw(t, ability, dc as i64, RollMode::Normal)?;
+                    ret.push_str(&st);
+                    ret.push_str("\n");
+                    if let Some((total, typ)) = dmg {
+                        let mut taken = match (passed, abi.half_on_save) {
+                            (false, _) => total,
+                            (true, true) => total / 2,
+                            (true, false) => 0
+                        };
+                        if taken == 0 {
+                            ret.push_str(&format!("{} takes no damage.\n\n", t.name));
+                            continue;
+                        }
+                        if passed {
+                            ret.push_str(&format!("Half damage: <b>{}</b>\n", taken));
+                        }
+                        if let Some(ref typ) = typ {
+                            let (new_dmg, st) = self.apply_defenses(t, taken, typ)?;
+                            taken = new_dmg;
+                            ret.push_str(&st);
+                        }
+                        let (_, st) = self.apply_damage(t, taken, false)?;
+                        ret.push_str(&st);
+                        ret.push_str("\n");
+                    }
+                }
+            }
+            else {
+                let from = self.ability_attacker(user, abi)?;
+                for t in targets {
+                    let st = self.attack(&from, t, mode)?;
+                    ret.push_str(&st);
+                    ret.push_str("\n\n");
+                }
+            }
+            if let Some(rounds) = abi.duration {
+                if self.cur_round > 0 {
+                    self.add_effect(&abi.name, "ability", user, user, rounds)?;
+                    ret.push_str(&format!("This lasts for {} rounds.", rounds));
+                }
+            }
+            Ok(ret.trim_right().to_string())
+        })
+    }
+    /// Has a monster make all the attacks in its multiattack (or just its
+    /// current attack, if it hasn't got one) against a target.
+    fn monster_turn(&mut self, from: &Combatant, to: &Combatant, mode: RollMode) -> Result<String> {
+        let mons_id = from.monster_id.ok_or("The current combatant isn't a monster.")?;
+        let mons = mdsl::monsters.filter(mdsl::id.eq(mons_id))
+            .get_result::<Monster>(&*self.db.borrow())?;
+        if mons.multiattack.len() == 0 {
+            return self.attack(from, to, mode);
+        }
+        let abis = adsl::abilities.filter(adsl::monster_id.eq(mons_id))
+            .load::<Ability>(&*self.db.borrow())?;
+        let mut ret = format!("<b>{}</b> makes {} attacks: {}\n\n", from.name, mons.multiattack.len(), mons.multiattack.join(", "));
+        let mut to = to.clone();
+        for name in mons.multiattack.iter() {
+            let abi = abis.iter().find(|a| &a.name == name)
+                .ok_or_else(|| format!("{} has no ability called {}.", mons.name, name))?;
+            let from = self.ability_attacker(from, abi)?;
+            ret.push_str(&format!("<b>{}</b>:\n", abi.name));
+            let st = self.attack(&from, &to, mode)?;
+            ret.push_str(&st);
+            ret.push_str("\n\n");
+            to = cdsl::combatants.filter(cdsl::id.eq(to.id))
+                .get_result::<Combatant>(&*self.db.borrow())?;
+            if to.dead {
+                ret.push_str(&format!("{} is dead, so {} stops attacking.", to.name, from.name));
+                break;
+            }
+        }
+        Ok(ret.trim_right().to_string())
+    }
     fn on_command(&mut self, nick: &str, to: &str, args: &[&str]) -> Result<()> {
         match &args as &[_] {
             &["ping"] => self.msg(to, "Pong!")?,
@@ -801,9 +2904,28 @@ impl Conn {
                     .execute(&*self.db.borrow());
                 self.msg(to, &format!("Result: {:?}", res))?;
             },
-            &[x @ "chk", what] | &[x @ "check", what] | &["pchk", x, what] => {
+            &[x @ "chk", what] | &[x @ "check", what] | &["pchk", x, what] => {
+                let player = self.authenticate_nick_or_dm(x, nick)?;
+                let st = self.check(&player, what, RollMode::Normal, Roller::Player(player.id))?;
+                self.msg(&to, &st)?;
+            }
+            &["save", comb, ability, dc] => {
+                let comb = self.query_combatant(comb)?;
+                self.check_own_combatant(nick, &comb)?;
+                let (_, st) = self.saving_throw(&comb, ability, dc.parse()?, RollMode::Normal)?;
+                self.msg(&to, &st)?;
+            },
+            &["save", comb, ability, dc, mode] => {
+                let mode = RollMode::parse(mode)?;
+                let comb = self.query_combatant(comb)?;
+                self.check_own_combatant(nick, &comb)?;
+                let (_, st) = self.saving_throw(&comb, ability, dc.parse()?, mode)?;
+                self.msg(&to, &st)?;
+            },
+            &[x @ "chk", what, mode] | &[x @ "check", what, mode] | &["pchk", x, what, mode] => {
+                let mode = RollMode::parse(mode)?;
                 let player = self.authenticate_nick_or_dm(x, nick)?;
-                let st = self.check(&player, what)?;
+                let st = self.check(&player, what, mode, Roller::Player(player.id))?;
                 self.msg(&to, &st)?;
             }
             &[x @ "atk", tgt] | &[x @ "attack", tgt] | &["patk", x, tgt] => {
@@ -813,13 +2935,52 @@ impl Conn {
                 if comb.player_id.is_none() || comb.player_id.unwrap() != player.id {
                     bail!("It's not your turn.");
                 }
-                let st = self.attack(&comb, &tgt)?;
+                let mut st = self.attack(&comb, &tgt, RollMode::Normal)?;
+                st += &self.check_victory(to)?;
+                self.msg(&to, &st)?;
+            },
+            &[x @ "atk", tgt, mode] | &[x @ "attack", tgt, mode] | &["patk", x, tgt, mode] => {
+                let mode = RollMode::parse(mode)?;
+                let player = self.authenticate_nick_or_dm(x, nick)?;
+                let comb = self.get_current_combatant()?;
+                let tgt = self.query_combatant(tgt)?;
+                if comb.player_id.is_none() || comb.player_id.unwrap() != player.id {
+                    bail!("It's not your turn.");
+                }
+                let mut st = self.attack(&comb, &tgt, mode)?;
+                st += &self.check_victory(to)?;
                 self.msg(&to, &st)?;
             },
             &["catk", tgt] => {
                 let comb = self.get_current_combatant()?;
                 let tgt = self.query_combatant(tgt)?;
-                let st = self.attack(&comb, &tgt)?;
+                let mut st = self.attack(&comb, &tgt, RollMode::Normal)?;
+                st += &self.check_victory(to)?;
+                self.msg(&to, &st)?;
+            },
+            &["catk", tgt, mode] => {
+                let mode = RollMode::parse(mode)?;
+                let comb = self.get_current_combatant()?;
+                let tgt = self.query_combatant(tgt)?;
+                let mut st = self.attack(&comb, &tgt, mode)?;
+                st += &self.check_victory(to)?;
+                self.msg(&to, &st)?;
+            },
+            &["cturn", tgt] => {
+                self.check_admin(&nick)?;
+                let comb = self.get_current_combatant()?;
+                let tgt = self.query_combatant(tgt)?;
+                let mut st = self.monster_turn(&comb, &tgt, RollMode::Normal)?;
+                st += &self.check_victory(to)?;
+                self.msg(&to, &st)?;
+            },
+            &["cturn", tgt, mode] => {
+                self.check_admin(&nick)?;
+                let mode = RollMode::parse(mode)?;
+                let comb = self.get_current_combatant()?;
+                let tgt = self.query_combatant(tgt)?;
+                let mut st = self.monster_turn(&comb, &tgt, mode)?;
+                st += &self.check_victory(to)?;
                 self.msg(&to, &st)?;
             },
             &["unassigned", x @ "items"] | &["unassigned", x @ "abis"] => {
@@ -884,39 +3045,166 @@ impl Conn {
             },
             &["buff", "add", player, name] => {
                 self.check_admin(nick)?;
-                let mut player = self.query_player(player)?;
-                self.load_buff(name)?;
-                self.interp.call("buff", vec![
-                    to.into(),
-                    player.id.into()
-                ]).map_err(|e| self.interp.format_error(&e))?;
-                player.buffs.push(name.into());
-                diesel::update(pdsl::players.filter(pdsl::id.eq(player.id)))
-                    .set(pdsl::buffs.eq(player.buffs))
-                    .execute(&*self.db.borrow())?;
+                let player = self.query_player(player)?;
+                self.add_buff(to, player, name)?;
                 self.msg(&to, "Buff applied.")?;
             },
+            &["buff", "add", player, name, rounds] => {
+                self.check_admin(nick)?;
+                let rounds = rounds.parse::<i32>()?;
+                let player = self.query_player(player)?;
+                let comb = self.player_combatant(player.id)?
+                    .ok_or("That player isn't in the encounter.")?;
+                let source = self.get_current_combatant()?;
+                let buff = self.query_buff(name)?;
+                if player.buffs.contains(&buff.name) {
+                    bail!("That buff is already acting on that player.");
+                }
+                // put the effect on first, so the buff is never left without one to expire it
+                let eff = self.add_effect(&buff.name, "buff", &comb, &source, rounds)?;
+                if let Err(e) = self.add_buff(to, player, name) {
+                    diesel::delete(edsl::effects.filter(edsl::id.eq(eff.id)))
+                        .execute(&*self.db.borrow())?;
+                    return Err(e);
+                }
+                self.msg(&to, &format!("Buff applied for {} rounds.", rounds))?;
+            },
             &["buff", "remove", player, name] => {
+                self.check_admin(nick)?;
+                let player = self.query_player(player)?;
+                self.remove_buff(to, player, name)?;
+                self.msg(&to, "Buff removed.")?;
+            },
+            &["effect", "add", comb, name, rounds] => {
+                self.check_admin(nick)?;
+                let rounds = rounds.parse::<i32>()?;
+                let comb = self.query_combatant(comb)?;
+                let source = self.get_current_combatant()?;
+                self.add_effect(name, "effect", &comb, &source, rounds)?;
+                self.msg(&to, &format!("{} is affected by <b>{}</b> for {} rounds.", comb.name, name, rounds))?;
+            },
+            &["effect", "end", id] => {
+                self.check_admin(nick)?;
+                let id = id.parse::<i32>()?;
+                let eff = edsl::effects.filter(edsl::id.eq(id))
+                    .get_result::<Effect>(&*self.db.borrow())?;
+                let st = self.expire_effect(to, &eff)?;
+                self.msg(&to, &st)?;
+            },
+            &["condition", "add", comb, name] => {
+                self.check_admin(nick)?;
+                let comb = self.query_combatant(comb)?;
+                let comb = self.add_condition(&comb, name)?;
+                let st = self.print_combatant(&comb, true);
+                self.msg(&to, &st)?;
+            },
+            &["condition", "add", comb, name, rounds] => {
+                self.check_admin(nick)?;
+                let rounds = rounds.parse::<i32>()?;
+                let comb = self.query_combatant(comb)?;
+                let source = self.get_current_combatant()?;
+                let cond = conditions::find(name)?;
+                if self.cur_round == 0 || rounds < 1 {
+                    bail!("Conditions can only be timed for a round or more, during an encounter.");
+                }
+                let comb = self.add_condition(&comb, name)?;
+                self.add_effect(cond.name, "condition", &comb, &source, rounds)?;
+                let st = format!("{}\n(for {} rounds)", self.print_combatant(&comb, true), rounds);
+                self.msg(&to, &st)?;
+            },
+            &["condition", "remove", comb, name] => {
+                self.check_admin(nick)?;
+                let comb = self.query_combatant(comb)?;
+                let comb = self.remove_condition(&comb, name)?;
+                let st = self.print_combatant(&comb, true);
+                self.msg(&to, &st)?;
+            },
+            &["condition", name] => {
+                let cond = conditions::find(name)?;
+                self.msg(&to, &format!("<b>{}</b>: {}", cond.name, cond.descrip))?;
+            },
+            &["conditions"] => {
+                let st = conditions::CONDITIONS.iter()
+                    .map(|c| format!("<b>{}</b>: {}", c.name, c.descrip))
+                    .collect::<Vec<_>>()
+                    .join("\n");
+                self.msg(&to, &st)?;
+            },
+            &["defense", op @ "add", player, kind, typ] | &["defense", op @ "remove", player, kind, typ] => {
                 self.check_admin(nick)?;
                 let mut player = self.query_player(player)?;
-                if !player.buffs.contains(&name.into()) {
-                    bail!("No such buff is acting on that player at this time.");
+                let typ = damage::parse_type(typ)?.to_string();
+                {
+                    let list = match kind {
+                        "vuln" | "vulnerable" => &mut player.damage_vulnerabilities,
+                        "resist" | "resistant" => &mut player.damage_resistances,
+                        "immune" => &mut player.damage_immunities,
+                        _ => bail!("Unknown kind of defense (try 'resist', 'vuln' or 'immune')")
+                    };
+                    list.retain(|t| t != &typ);
+                    if op == "add" {
+                        list.push(typ);
+                    }
                 }
-                self.load_buff(name)?;
-                self.interp.call("debuff", vec![
-                    to.into(),
-                    player.id.into()
-                ]).map_err(|e| self.interp.format_error(&e))?;
-                player.buffs.retain(|b| b != name);
                 diesel::update(pdsl::players.filter(pdsl::id.eq(player.id)))
-                    .set(pdsl::buffs.eq(player.buffs))
+                    .set((pdsl::damage_vulnerabilities.eq(player.damage_vulnerabilities.clone()),
+                          pdsl::damage_resistances.eq(player.damage_resistances.clone()),
+                          pdsl::damage_immunities.eq(player.damage_immunities.clone())))
                     .execute(&*self.db.borrow())?;
-                self.msg(&to, "Buff removed.")?;
+                let st = self.print_player(&player);
+                self.msg(&to, &st)?;
+            },
+            &["heal", tgt, what] => {
+                let tgt = self.query_combatant(tgt)?;
+                // the DM can heal with whatever dice they like
+                let st = if nick == self.admin {
+                    let roll = self.roll_dice(what, Roller::Sender)?;
+                    let (_, st) = self.heal(&tgt, roll.total as i32)?;
+                    format!("Healing: {}\n{}", roll, st)
+                }
+                else {
+                    let player = self.authenticate_nick(nick)?;
+                    self.heal_with_ability(&player, &tgt, what)?
+                };
+                self.msg(&to, &st)?;
+            },
+            &["pheal", pid, tgt, what] => {
+                self.check_admin(nick)?;
+                let player = self.authenticate_nick_or_dm(pid, nick)?;
+                let tgt = self.query_combatant(tgt)?;
+                let st = self.heal_with_ability(&player, &tgt, what)?;
+                self.msg(&to, &st)?;
+            },
+            &["temphp", tgt, what] => {
+                self.check_admin(nick)?;
+                let tgt = self.query_combatant(tgt)?;
+                let roll = self.roll_dice(what, Roller::Sender)?;
+                let (_, st) = self.give_temp_hp(&tgt, roll.total as i32)?;
+                self.msg(&to, &format!("Temporary HP: {}\n{}", roll, st))?;
+            },
+            &["stabilize", comb] | &["stabilise", comb] => {
+                self.check_admin(nick)?;
+                let comb = self.query_combatant(comb)?;
+                if comb.dead || comb.cur_hp > 0 || comb.player_id.is_none() {
+                    bail!("{} isn't dying.", comb.name);
+                }
+                let comb = diesel::update(cdsl::combatants.filter(cdsl::id.eq(comb.id)))
+                    .set((cdsl::stable.eq(true),
+                          cdsl::death_successes.eq(0),
+                          cdsl::death_failures.eq(0)))
+                    .get_result::<Combatant>(&*self.db.borrow())?;
+                let mut st = self.print_combatant(&comb, true);
+                st += &self.check_victory(to)?;
+                self.msg(&to, &st)?;
+            },
+            &["effects"] => {
+                let st = self.print_effects()?;
+                self.msg(&to, &st)?;
             },
             &["room", "enter", room] => {
                 self.check_admin(nick)?;
                 let rm = self.query_room(room)?;
-                let st = self.enter_room(&rm)?;
+                let st = self.enter_room(to, &rm)?;
                 self.msg(&to, &st)?;
             },
             &["room", "describe", room] => {
@@ -949,6 +3237,21 @@ impl Conn {
                 let st = self.drop(&player, &item)?;
                 self.msg(&to, &st)?;
             },
+            &["longrest"] => {
+                self.check_admin(nick)?;
+                let st = self.long_rest()?;
+                self.msg(&to, &st)?;
+            },
+            &["revive", player] | &["revive", player, _] => {
+                self.check_admin(nick)?;
+                let hp = match args.get(2) {
+                    Some(hp) => hp.parse()?,
+                    None => 1
+                };
+                let player = self.query_player(player)?;
+                let st = self.revive(&player, hp)?;
+                self.msg(&to, &st)?;
+            },
             &["recover_uses"] => {
                 self.check_admin(nick)?;
                 let st = format!("{} abilities changed.", self.recover_uses()?);
@@ -959,14 +3262,98 @@ impl Conn {
                 let st = self.begin_encounter()?;
                 self.msg(&to, &st)?;
             },
+            &["encounter", "difficulty"] => {
+                self.check_admin(nick)?;
+                let combs = cdsl::combatants.filter(cdsl::monster_id.is_not_null())
+                    .load::<Combatant>(&*self.db.borrow())?;
+                let mut mons = vec![];
+                for c in combs {
+                    if let Some(mid) = c.monster_id {
+                        mons.push(mdsl::monsters.filter(mdsl::id.eq(mid))
+                                  .get_result::<Monster>(&*self.db.borrow())?);
+                    }
+                }
+                if mons.len() == 0 {
+                    bail!("There aren't any monster combatants. (Try encounter/difficulty/[monster,monster...].)");
+                }
+                let st = self.rate_encounter(&mons)?;
+                self.msg(&to, &st)?;
+            },
+            &["encounter", "difficulty", names] => {
+                self.check_admin(nick)?;
+                let mut mons = vec![];
+                for name in names.split(',') {
+                    mons.push(self.query_monster(name.trim())?);
+                }
+                let st = self.rate_encounter(&mons)?;
+                self.msg(&to, &st)?;
+            },
+            &["encounter", "generate", rating, ref filters..] => {
+                self.check_admin(nick)?;
+                let (mut typ, mut env, mut dry_run) = (None, None, false);
+                for f in filters {
+                    match &f.split('=').collect::<Vec<_>>() as &[_] {
+                        &["dry"] | &["dryrun"] => dry_run = true,
+                        &["type", t] => typ = Some(t),
+                        &["env", e] => env = Some(e),
+                        _ => bail!("Unknown filter '{}' (try type=[type], env=[environment] or dry)", f)
+                    }
+                }
+                let mons = self.pick_monsters(rating, typ, env)?;
+                let mut st = self.rate_encounter(&mons)?;
+                if dry_run {
+                    st += "\n(Dry run: nothing was spawned.)";
+                }
+                else {
+                    st += "\nSpawned:";
+                    let mut inits = String::new();
+                    for m in mons.iter() {
+                        let (comb, desc) = self.monster_to_combatant(m)?;
+                        st += &format!("\n- <b>{}</b>: {}", comb.name, desc);
+                        // monsters joining a fight that's already going need their initiative
+                        if self.cur_round > 0 {
+                            inits += &format!("\n{}", self.set_initiative(&comb)?);
+                        }
+                    }
+                    if self.cur_round > 0 {
+                        st += &format!("\n\nRolling initiative...\n{}{}", inits, self.break_ties()?);
+                    }
+                }
+                self.msg(&to, &st)?;
+            },
+            &["hpmode"] => {
+                self.check_admin(nick)?;
+                let global = self.get_prop("hp_mode")?.unwrap_or("average".into());
+                let mut st = format!("Monsters get {} HP.", global);
+                if let Some(m) = self.get_prop("encounter_hp_mode")? {
+                    st = format!("Monsters get {} HP in this encounter (and {} HP otherwise).", m, global);
+                }
+                self.msg(&to, &st)?;
+            },
+            &["hpmode", mode] | &["hpmode", mode, "encounter"] => {
+                self.check_admin(nick)?;
+                if mode != "average" && mode != "roll" && mode != "max" {
+                    bail!("The HP mode should be average, roll or max.");
+                }
+                if args.len() == 3 {
+                    self.set_prop("encounter_hp_mode", mode)?;
+                    self.msg(&to, &format!("Monsters will get {} HP until this encounter ends (or another room is entered).", mode))?;
+                }
+                else {
+                    self.set_prop("hp_mode", mode)?;
+                    self.msg(&to, &format!("Monsters will get {} HP from now on.", mode))?;
+                }
+            },
             &["encounter", "end"] => {
                 self.check_admin(nick)?;
-                let st = self.end_encounter()?;
+                let st = self.end_encounter(to)?;
                 self.msg(&to, &st)?;
             },
             &["nextturn"] => {
                 self.check_admin(nick)?;
-                let st = self.advance_turn()?;
+                let mut st = self.advance_turn(to)?;
+                // death saves made at the start of a turn can finish off the party
+                st += &self.check_victory(to)?;
                 self.msg(&to, &st)?;
             },
             &["init=", id, val] => {
@@ -974,9 +3361,48 @@ impl Conn {
                 let val = val.parse::<i32>()?;
                 let comb = self.query_combatant(id)?;
                 diesel::update(cdsl::combatants.filter(cdsl::id.eq(comb.id)))
-                    .set(cdsl::initiative.eq(val))
+                    .set((cdsl::initiative.eq(val), cdsl::init_tiebreak.eq(0)))
                     .execute(&*self.db.borrow())?;
-                let st = self.print_combatant(&comb, true);
+                let mut st = self.print_combatant(&comb, true);
+                st += &self.break_ties()?;
+                self.msg(&to, &st)?;
+            },
+            &["init", "reroll", id] => {
+                self.check_admin(nick)?;
+                let comb = self.query_combatant(id)?;
+                let mut st = self.set_initiative(&comb)?;
+                st += &self.break_ties()?;
+                st += &format!("\n{}", self.describe_slot(&comb)?);
+                self.msg(&to, &st)?;
+            },
+            &["encounter", "add", kind, id] => {
+                self.check_admin(nick)?;
+                if self.cur_round == 0 {
+                    bail!("An encounter is not taking place.");
+                }
+                let (comb, hp_desc) = match kind {
+                    "monster" | "mons" => {
+                        let mons = self.query_monster(id)?;
+                        let (comb, desc) = self.monster_to_combatant(&mons)?;
+                        (comb, Some(desc))
+                    },
+                    "player" => {
+                        let player = self.query_player(id)?;
+                        if self.player_combatant(player.id)?.is_some() {
+                            bail!("{} is already in the encounter.", player.name);
+                        }
+                        (self.player_to_combatant(&player)?, None)
+                    },
+                    "combatant" | "comb" => (self.query_combatant(id)?, None),
+                    _ => bail!("You can add a monster, player or combatant.")
+                };
+                let mut st = format!("{} joins the encounter!\n", comb.name);
+                if let Some(desc) = hp_desc {
+                    st += &format!("{} gets {}.\n", comb.name, desc);
+                }
+                st += &self.set_initiative(&comb)?;
+                st += &self.break_ties()?;
+                st += &format!("\n{}", self.describe_slot(&comb)?);
                 self.msg(&to, &st)?;
             },
             &["mabis", id] => {
@@ -989,8 +3415,8 @@ impl Conn {
             },
             &["mtoc", id] => {
                 let mons = self.query_monster(id)?;
-                let comb = self.monster_to_combatant(&mons)?;
-                let st = self.print_combatant(&comb, true);
+                let (comb, desc) = self.monster_to_combatant(&mons)?;
+                let st = format!("{}\n{} gets {}.", self.print_combatant(&comb, true), comb.name, desc);
                 self.msg(&to, &st)?;
             },
             &["ptoc", id] => {
@@ -1028,6 +3454,7 @@ impl Conn {
             &["set_current_combatant", id] => {
                 let id = id.parse::<i32>()?;
                 self.cur_combatant = Some(id);
+                self.save_encounter()?;
                 self.msg(&to, "Done.")?;
             },
             &[x @ "use", id] | &["puse", x, id] => {
@@ -1042,23 +3469,53 @@ impl Conn {
                 let mut ret = format!("{} uses {}!\n", player.name, abi.name);
                 let st = self.print_ability(&abi, false);
                 ret.push_str(&st);
-                if let Some(ref dice) = abi.damage_dice {
+                if abi.healing {
+                    ret.push_str(&format!("\n(Heal someone with heal/[target]/{}.)", abi.id));
+                }
+                else if let Some(attack) = ability_attack(&abi) {
                     let comb = diesel::update(cdsl::combatants.filter(cdsl::player_id.eq(player.id)))
-                        .set(cdsl::attack.eq(dice))
+                        .set(cdsl::attack.eq(attack))
                         .get_result::<Combatant>(&*self.db.borrow())?;
                     ret.push_str(&format!("\n{}'s new attack: {}", comb.name, comb.attack));
                 }
-                if let Some(ab) = abi.attack_bonus {
+                let attack_bonus = match abi.to_hit {
+                    Some(ref th) => Some(self.evaluate_bonus(th, &player)?),
+                    None => abi.attack_bonus
+                };
+                if let Some(ab) = attack_bonus {
                     let comb = diesel::update(cdsl::combatants.filter(cdsl::player_id.eq(player.id)))
                         .set(cdsl::attack_bonus.eq(ab))
                         .get_result::<Combatant>(&*self.db.borrow())?;
                     ret.push_str(&format!("\n{}'s new to hit bonus: {}", comb.name, comb.attack_bonus));
                 }
+                if let Some(rounds) = abi.duration {
+                    if let Some(comb) = self.player_combatant(player.id)? {
+                        if self.cur_round > 0 {
+                            self.add_effect(&abi.name, "ability", &comb, &comb, rounds)?;
+                            ret.push_str(&format!("\nThis lasts for {} rounds.", rounds));
+                        }
+                    }
+                }
                 if abi.uses_left != -1 {
                     diesel::update(adsl::abilities.filter(adsl::id.eq(abi.id)))
                         .set(adsl::uses_left.eq(abi.uses_left - 1))
                         .execute(&*self.db.borrow())?;
                 }
+                self.msg(&to, &ret)?;
+            },
+            &[x @ "use", id, tgts] | &["puse", x, id, tgts] => {
+                let id = id.parse::<i32>()?;
+                let player = self.authenticate_nick_or_dm(x, nick)?;
+                let abi = adsl::abilities.filter(adsl::id.eq(id))
+                    .filter(adsl::player_id.eq(player.id))
+                    .get_result::<Ability>(&*self.db.borrow())?;
+                let comb = self.get_current_combatant()?;
+                if comb.player_id.is_none() || comb.player_id.unwrap() != player.id {
+                    bail!("It's not your turn.");
+                }
+                let tgts = self.query_combatants(tgts)?;
+                let mut st = self.use_on(&comb, &abi, &tgts, RollMode::Normal)?;
+                st += &self.check_victory(to)?;
                 self.msg(&to, &st)?;
             },
             &["cuse", id] => {
@@ -1069,21 +3526,46 @@ impl Conn {
                 let abi = adsl::abilities.filter(adsl::id.eq(id))
                     .filter(adsl::monster_id.eq(mons_id))
                     .get_result::<Ability>(&*self.db.borrow())?;
+                self.spend_use(&comb, &abi)?;
                 let mut ret = format!("{} uses {}!\n", comb.name, abi.name);
                 let st = self.print_ability(&abi, false);
                 ret.push_str(&st);
-                if let Some(ref dice) = abi.damage_dice {
+                if let Some(attack) = ability_attack(&abi) {
                     let comb = diesel::update(cdsl::combatants.filter(cdsl::id.eq(comb.id)))
-                        .set(cdsl::attack.eq(dice))
+                        .set(cdsl::attack.eq(attack))
                         .get_result::<Combatant>(&*self.db.borrow())?;
                     ret.push_str(&format!("\n{}'s new attack: {}", comb.name, comb.attack));
                 }
-                if let Some(ab) = abi.attack_bonus {
+                let attack_bonus = match abi.to_hit {
+                    Some(ref th) => {
+                        let attrs = self.creature_for(&comb)?;
+                        Some(self.evaluate_bonus(th, &attrs)?)
+                    },
+                    None => abi.attack_bonus
+                };
+                if let Some(ab) = attack_bonus {
                     let comb = diesel::update(cdsl::combatants.filter(cdsl::id.eq(comb.id)))
                         .set(cdsl::attack_bonus.eq(ab))
                         .get_result::<Combatant>(&*self.db.borrow())?;
                     ret.push_str(&format!("\n{}'s new to hit bonus: {}", comb.name, comb.attack_bonus));
                 }
+                if let Some(rounds) = abi.duration {
+                    self.add_effect(&abi.name, "ability", &comb, &comb, rounds)?;
+                    ret.push_str(&format!("\nThis lasts for {} rounds.", rounds));
+                }
+                self.msg(&to, &ret)?;
+            },
+            &["cuse", id, tgts] => {
+                self.check_admin(&nick)?;
+                let id = id.parse::<i32>()?;
+                let comb = self.get_current_combatant()?;
+                let mons_id = comb.monster_id.ok_or("The current combatant isn't a monster.")?;
+                let abi = adsl::abilities.filter(adsl::id.eq(id))
+                    .filter(adsl::monster_id.eq(mons_id))
+                    .get_result::<Ability>(&*self.db.borrow())?;
+                let tgts = self.query_combatants(tgts)?;
+                let mut st = self.use_on(&comb, &abi, &tgts, RollMode::Normal)?;
+                st += &self.check_victory(to)?;
                 self.msg(&to, &st)?;
             },
             &["findmons", id] => {
@@ -1140,7 +3622,15 @@ impl Conn {
                 }
                 else {
                     let player = self.authenticate_nick(nick)?;
-                    let st = self.print_player(&player);
+                    let mut st = self.print_player(&player);
+                    if let Some(c) = self.player_combatant(player.id)? {
+                        st.push_str(&format!("\nIn this encounter: HP {}/{}", c.cur_hp, c.max_hp));
+                    }
+                    let macros = self.get_macros(Some(player.id))?;
+                    if macros.len() > 0 {
+                        st.push_str("\nMacros:\n");
+                        st.push_str(&self.print_macros(&macros));
+                    }
                     self.msg(&to, &st)?;
                 }
             },
@@ -1165,7 +3655,7 @@ impl Conn {
                 self.check_admin(nick)?;
                 let max_hp = max_hp.parse::<i32>()?;
                 let armor_class = armor_class.parse::<i32>()?;
-                self.roll_dice(attack)?;
+                damage::validate_attack(attack)?;
                 let nm = NewCombatant {
                     name: name,
                     attack: attack,
@@ -1181,7 +3671,7 @@ impl Conn {
             },
             &["atk=", id, attack] => {
                 self.check_admin(nick)?;
-                self.roll_dice(attack)?;
+                damage::validate_attack(attack)?;
                 let comb = self.query_combatant(id)?;
                 let x = diesel::update(cdsl::combatants.filter(cdsl::id.eq(comb.id)))
                     .set(cdsl::attack.eq(attack))
@@ -1191,32 +3681,206 @@ impl Conn {
             &[a @ "hp", id, val] | &[a @ "hp=", id, val] => {
                 self.check_admin(nick)?;
                 let comb = self.query_combatant(id)?;
-                let mut val = val.parse::<i32>()?;
-                if a == "hp" {
-                    val = comb.cur_hp + val;
+                let val = val.parse::<i32>()?;
+                let (x, mut st) = if a == "hp" && val < 0 {
+                    self.apply_damage(&comb, -val as i64, false)?
                 }
-                let x = diesel::update(cdsl::combatants.filter(cdsl::id.eq(comb.id)))
-                    .set(cdsl::cur_hp.eq(val))
-                    .get_result::<Combatant>(&*self.db.borrow())?;
-                let st = self.print_combatant(&x, true);
+                else if a == "hp" {
+                    self.set_hp(&comb, comb.cur_hp.max(0) + val)?
+                }
+                else {
+                    self.set_hp(&comb, val)?
+                };
+                st += &self.print_combatant(&x, true);
+                st += &self.check_victory(to)?;
                 self.msg(&to, &st)?;
             },
-            &["roll", dice] => {
-                let r = self.roll_dice(dice)?;
-                self.msg(to, &format!("rolling {}: {}", dice, r))?;
+            &["roll", dice] | &["m", dice] => {
+                let dice = self.expand_macro(nick, dice)?;
+                let attrs = self.creature_for_nick(nick);
+                let r = self.roll_dice_as(&dice, &attrs, Roller::Sender)?;
+                self.msg(to, &format!("rolling {}", r))?;
+            },
+            &["sroll", dice] => {
+                self.check_admin(nick)?;
+                let dice = self.expand_macro(nick, dice)?;
+                let r = self.roll_dice(&dice, Roller::Sender)?;
+                self.dm_msg(&format!("Secret roll: rolling {}", r))?;
+                self.msg(to, "The DM rolls behind the screen.")?;
+            },
+            &["schk", player, what] | &["schk", player, what, _] => {
+                self.check_admin(nick)?;
+                let mode = match args.get(3) {
+                    Some(m) => RollMode::parse(m)?,
+                    None => RollMode::Normal
+                };
+                let player = self.query_player(player)?;
+                // recorded as the DM's roll, so the player can't see it in their history
+                let st = self.check(&player, what, mode, Roller::Sender)?;
+                self.dm_msg(&format!("Secret {} check for {}:\n{}", what, player.name, st))?;
+                self.msg(to, "The DM rolls behind the screen.")?;
+            },
+            &["dmroom"] => {
+                self.check_admin(nick)?;
+                self.set_prop("dm_room", to)?;
+                self.msg(to, "Okay, I'll send secret rolls here.")?;
+            },
+            &["roll", dice, mode] | &["m", dice, mode] => {
+                let mode = RollMode::parse(mode)?;
+                let dice = self.expand_macro(nick, dice)?;
+                let attrs = self.creature_for_nick(nick);
+                let first = self.roll_dice_as(&dice, &attrs, Roller::Sender)?;
+                let mut st = format!("rolling {}", first);
+                let mut rolls = vec![first.total];
+                if mode != RollMode::Normal {
+                    let second = self.roll_dice_as(&dice, &attrs, Roller::Sender)?;
+                    st.push_str(&format!("\nrolling {}", second));
+                    rolls.push(second.total);
+                }
+                let r = D20Roll::new(rolls, mode);
+                st.push_str(&format!("\nkeeping the result {}: {} => <b>{}</b>", mode.describe(), r.list(), r.result));
+                self.msg(to, &st)?;
             },
             &["reroll"] => {
-                match self.last_roll.clone() {
-                    Some(s) => {
-                        let r = self.roll_dice(&s)?;
-                        self.msg(to, &format!("rerolling {}: {}", s, r))?;
+                let last = rodsl::rolls.filter(rodsl::nick.eq(nick))
+                    .order(rodsl::id.desc())
+                    .limit(1)
+                    .load::<Roll>(&*self.db.borrow())?;
+                match last.into_iter().next() {
+                    Some(last) => {
+                        let attrs = self.creature_for_nick(nick);
+                        let r = self.roll_dice_as(&last.spec, &attrs, Roller::Sender)?;
+                        self.msg(to, &format!("rerolling {}", r))?;
                     },
                     None => self.msg(to, &format!("You'll have to roll something first."))?
                 }
             },
+            &["macro", "set", name, spec] => {
+                if !name.chars().all(|c| c.is_alphanumeric()) || dice::parse(name).is_ok() {
+                    bail!("Macro names must be alphanumeric, and not look like dice.");
+                }
+                dice::parse(spec)?;
+                let pid = match self.check_admin(nick) {
+                    Ok(_) => None,
+                    Err(_) => Some(self.authenticate_nick(nick)?.id)
+                };
+                let name = name.to_lowercase();
+                self.delete_macro(&name, pid)?;
+                diesel::insert(&NewMacro { name: &name, spec, player_id: pid }).into(madsl::macros)
+                    .execute(&*self.db.borrow())?;
+                let scope = if pid.is_none() { "global macro" } else { "macro" };
+                self.msg(to, &format!("Set {} <b>{}</b> to {}.", scope, name, spec))?;
+            },
+            &["macro", "del", name] => {
+                let pid = match self.check_admin(nick) {
+                    Ok(_) => None,
+                    Err(_) => Some(self.authenticate_nick(nick)?.id)
+                };
+                if self.delete_macro(name, pid)? == 0 {
+                    bail!("You don't have a macro with that name.");
+                }
+                self.msg(to, "Macro deleted.")?;
+            },
+            &["macro", "list"] | &["macros"] => {
+                let pid = self.authenticate_nick(nick).ok().map(|p| p.id);
+                let macros = self.get_macros(pid)?;
+                let st = self.print_macros(&macros);
+                self.msg(to, &st)?;
+            },
+            &["log"] => {
+                let st = self.print_log(10)?;
+                self.msg(&to, &st)?;
+            },
+            &["log", n] => {
+                let st = self.print_log(n.parse()?)?;
+                self.msg(&to, &st)?;
+            },
+            &["undo"] => {
+                self.check_admin(nick)?;
+                let st = self.undo(1)?;
+                self.msg(&to, &st)?;
+            },
+            &["undo", n] => {
+                self.check_admin(nick)?;
+                let st = self.undo(n.parse()?)?;
+                self.msg(&to, &st)?;
+            },
+            &["rolls"] => {
+                let st = self.print_rolls(nick, 10)?;
+                self.msg(to, &st)?;
+            },
+            &["rolls", n] => {
+                let n = n.parse::<i64>()?;
+                if n < 1 {
+                    bail!("Ask for at least one roll.");
+                }
+                let st = self.print_rolls(nick, n)?;
+                self.msg(to, &st)?;
+            },
+            &["rollstats"] => {
+                let player = self.authenticate_nick(nick)?;
+                let st = self.print_roll_stats(&player)?;
+                self.msg(to, &st)?;
+            },
+            &["rollstats", player] => {
+                let player = self.query_player(player)?;
+                let st = self.print_roll_stats(&player)?;
+                self.msg(to, &st)?;
+            },
+            &["odds", "hit", from, tgt] => {
+                let from = self.query_combatant(from)?;
+                let tgt = self.query_combatant(tgt)?;
+                let st = self.hit_odds(&from, &tgt, RollMode::Normal)?;
+                self.msg(to, &st)?;
+            },
+            &["odds", "hit", from, tgt, mode] => {
+                let mode = RollMode::parse(mode)?;
+                let from = self.query_combatant(from)?;
+                let tgt = self.query_combatant(tgt)?;
+                let st = self.hit_odds(&from, &tgt, mode)?;
+                self.msg(to, &st)?;
+            },
+            &["odds", dice] => {
+                let dice = self.expand_macro(nick, dice)?;
+                let attrs = self.creature_for_nick(nick);
+                let dist = odds::distribution(&dice::parse(&dice)?, &attrs)?;
+                let st = self.print_odds(&dice, &dist, RollMode::Normal);
+                self.msg(to, &st)?;
+            },
+            &["odds", dice, mode] => {
+                let mode = RollMode::parse(mode)?;
+                let dice = self.expand_macro(nick, dice)?;
+                let attrs = self.creature_for_nick(nick);
+                let dist = odds::distribution(&dice::parse(&dice)?, &attrs)?
+                    .with_mode(mode);
+                let st = self.print_odds(&dice, &dist, mode);
+                self.msg(to, &st)?;
+            },
+            &["seed"] => {
+                self.check_admin(nick)?;
+                let st = {
+                    let rng = self.dice.rng.borrow();
+                    format!("Dice seed: <b>{}</b> ({} numbers drawn so far)", rng.seed(), rng.draws())
+                };
+                self.msg(to, &st)?;
+            },
+            &["seed", seed] => {
+                self.check_admin(nick)?;
+                let seed = seed.parse::<u64>()?;
+                *self.dice.rng.borrow_mut() = DiceRng::new(seed);
+                self.msg(to, &format!("Dice reseeded with {}.", seed))?;
+            },
+            &["replay", seed, draws, dice] => {
+                self.check_admin(nick)?;
+                let seed = seed.parse::<u64>()?;
+                let draws = draws.parse::<u64>()?;
+                let mut rng = DiceRng::replay(seed, draws);
+                let r = dice::parse(dice)?.roll(&mut rng, &dice::NoAttributes)?;
+                self.msg(to, &format!("replaying (seed {}, draw {}) {}", seed, draws, r))?;
+            },
             &["quit"] => {
                 self.check_admin(nick)?;
-                self.end_encounter()?;
+                self.end_encounter(to)?;
                 self.msg(to, &format!("So long, and thanks for all the fish!"))?;
                 panic!("They asked us to quit, so we did.");
             },
@@ -1231,7 +3895,21 @@ impl Conn {
         if msg.chars().nth(0).unwrap() == ',' {
             msg.remove(0);
             let args = msg.split("/").collect::<Vec<&str>>();
-            if let Err(e) = self.on_command(&nick, &to, &args) {
+            *self.dice.context.borrow_mut() = Some((nick.to_string(), to.to_string()));
+            let before = if combatlog::is_logged(&args) {
+                self.snapshot().ok()
+            }
+            else {
+                None
+            };
+            let res = self.on_command(&nick, &to, &args);
+            if let Some(before) = before {
+                if let Err(e) = self.log_action(nick, &msg, &before) {
+                    println!("<{}> couldn't log command: {}", nick, e);
+                }
+            }
+            *self.dice.context.borrow_mut() = None;
+            if let Err(e) = res {
                 println!("<{}> encountered error: {}", nick, e);
                 let _ = self.msg(&to, &format!("ERROR: {}", e));
             }
@@ -1272,7 +3950,19 @@ fn main() {
     let client = Rc::new(RefCell::new(MatrixClient::login(&username, &password, &server).unwrap()));
     println!("[+] Initialising Ketos scripting environment");
     let interp = Interpreter::new();
-    ketos_fn!{ interp.scope() => "roll" => fn k_roll_dice(spec: &str) -> i64 }
+    let rng = match env::var("DICE_SEED") {
+        Ok(seed) => DiceRng::new(seed.parse().expect("DICE_SEED must be a number")),
+        Err(_) => DiceRng::from_entropy()
+    };
+    println!("[+] Dice seed is {}", rng.seed());
+    let tray = DiceTray {
+        rng: Rc::new(RefCell::new(rng)),
+        db: connection.clone(),
+        context: Rc::new(RefCell::new(None)),
+        admin: admin.clone()
+    };
+    scripts::register_dice(interp.scope(), tray.clone());
+    scripts::register_saves(interp.scope(), client.clone(), tray.clone());
     scripts::register_players(interp.scope());
     scripts::register_matrix(interp.scope(), client.clone());
     interp.scope().add_named_value("db", ketos::Value::Foreign(Rc::new(scripts::Database {
@@ -1285,14 +3975,67 @@ fn main() {
     println!("[+] Starting event loop!");
     let mut conn = Conn {
         client: client,
-        last_roll: None,
         db: connection,
         admin: admin,
         interp: interp,
+        dice: tray,
         cur_combatant: None,
-        cur_room: None
+        cur_round: 0,
+        cur_room: None,
+        started_at: None
     };
+    println!("[+] Restoring encounter state");
+    conn.restore_encounter().unwrap();
     loop {
         conn.main().unwrap();
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use dice::NoAttributes;
+
+    /// Makes an attack with advantage and a +5 bonus against AC 15, dealing
+    /// 1d8+3 damage.
+    fn attack_with(rng: &mut DiceRng) -> AttackRoll {
+        AttackRoll::roll(RollMode::Advantage, 15 - 5, "1d8+3", false, |s| {
+            Ok(dice::parse(s)?.roll(rng, &NoAttributes)?)
+        }).unwrap()
+    }
+    #[test]
+    fn seeded_attack_hits() {
+        let atk = attack_with(&mut DiceRng::new(42));
+        assert_eq!(atk.d20.rolls, vec![19, 7]);
+        assert_eq!(atk.d20.result, 19);
+        assert!(atk.hit && !atk.crit);
+        assert_eq!(atk.damage.as_ref().unwrap().to_string(), "1d8+3: [1] + 3 = 4");
+        assert!(atk.crit_damage.is_none());
+        assert_eq!(atk.total_damage(), 4);
+    }
+    #[test]
+    fn seeded_attack_misses() {
+        let atk = attack_with(&mut DiceRng::new(26));
+        assert_eq!(atk.d20.rolls, vec![1, 1]);
+        assert!(!atk.hit && !atk.crit);
+        assert!(atk.damage.is_none());
+        assert_eq!(atk.total_damage(), 0);
+    }
+    #[test]
+    fn seeded_attack_crits() {
+        let atk = attack_with(&mut DiceRng::new(10));
+        assert_eq!(atk.d20.rolls, vec![5, 20]);
+        assert!(atk.hit && atk.crit);
+        assert_eq!(atk.damage.as_ref().unwrap().to_string(), "1d8+3: [3] + 3 = 6");
+        // only the d8 is rolled again
+        assert_eq!(atk.crit_damage.as_ref().unwrap().to_string(), "1d8: [1] = 1");
+        assert_eq!(atk.total_damage(), 7);
+    }
+    #[test]
+    fn seeded_attack_is_repeatable() {
+        let first = attack_with(&mut DiceRng::new(42));
+        let second = attack_with(&mut DiceRng::new(42));
+        assert_eq!(first.d20.rolls, second.d20.rolls);
+        assert_eq!(first.total_damage(), second.total_damage());
+    }
+}
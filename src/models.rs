@@ -1,9 +1,13 @@
-use super::schema::{combatants, monsters, abilities, items, rooms, players, props, spells, buffs};
+use super::schema::{combatants, monsters, abilities, items, rooms, players, props, spells, buffs, rolls, macros, effects, encounter_state, combat_actions, combat_changes};
+use std::time::SystemTime;
 
 pub fn default_qty() -> i32 {
     1
 }
-#[derive(Queryable)]
+pub fn default_level() -> i32 {
+    1
+}
+#[derive(Queryable, Clone)]
 pub struct Monster {
     pub id: i32,
     pub name: String,
@@ -18,6 +22,21 @@ pub struct Monster {
     pub charisma: i32,
     pub challenge_rating: String,
     pub room_id: Vec<i32>,
+    pub damage_vulnerabilities: Vec<String>,
+    pub damage_resistances: Vec<String>,
+    pub damage_immunities: Vec<String>,
+    pub condition_immunities: Vec<String>,
+    pub strength_save: Option<i32>,
+    pub dexterity_save: Option<i32>,
+    pub constitution_save: Option<i32>,
+    pub intelligence_save: Option<i32>,
+    pub wisdom_save: Option<i32>,
+    pub charisma_save: Option<i32>,
+    pub multiattack: Vec<String>,
+    /// Where the monster can be found (e.g. "forest"), for the encounter generator.
+    pub environments: Vec<String>,
+    /// e.g. "18d10" (to which the constitution modifier is added for each die)
+    pub hit_dice: Option<String>
 }
 #[derive(Insertable, Serialize, Deserialize)]
 #[table_name="monsters"]
@@ -35,6 +54,32 @@ pub struct NewMonster {
     pub challenge_rating: String,
     #[serde(default)]
     pub room_id: Vec<i32>,
+    #[serde(default)]
+    pub damage_vulnerabilities: Vec<String>,
+    #[serde(default)]
+    pub damage_resistances: Vec<String>,
+    #[serde(default)]
+    pub damage_immunities: Vec<String>,
+    #[serde(default)]
+    pub condition_immunities: Vec<String>,
+    #[serde(default)]
+    pub strength_save: Option<i32>,
+    #[serde(default)]
+    pub dexterity_save: Option<i32>,
+    #[serde(default)]
+    pub constitution_save: Option<i32>,
+    #[serde(default)]
+    pub intelligence_save: Option<i32>,
+    #[serde(default)]
+    pub wisdom_save: Option<i32>,
+    #[serde(default)]
+    pub charisma_save: Option<i32>,
+    #[serde(default)]
+    pub multiattack: Vec<String>,
+    #[serde(default)]
+    pub environments: Vec<String>,
+    #[serde(default)]
+    pub hit_dice: Option<String>
 }
 #[derive(Queryable, Serialize, Deserialize, Debug, AsChangeset)]
 pub struct Player {
@@ -51,9 +96,21 @@ pub struct Player {
     pub wisdom: i32,
     pub charisma: i32,
     pub initiative_bonus: i32,
-    pub buffs: Vec<String>
+    pub buffs: Vec<String>,
+    pub level: i32,
+    pub damage_vulnerabilities: Vec<String>,
+    pub damage_resistances: Vec<String>,
+    pub damage_immunities: Vec<String>,
+    pub save_proficiencies: Vec<String>,
+    pub xp: i32,
+    pub cur_hp: i32,
+    pub temp_hp: i32,
+    pub death_successes: i32,
+    pub death_failures: i32,
+    pub stable: bool,
+    pub dead: bool
 }
-#[derive(Insertable, Serialize, Deserialize)]
+#[derive(Insertable)]
 #[table_name="players"]
 pub struct NewPlayer {
     pub id: Option<i32>,
@@ -68,8 +125,15 @@ pub struct NewPlayer {
     pub constitution: i32,
     pub wisdom: i32,
     pub charisma: i32,
-    #[serde(default)]
-    pub initiative_bonus: i32
+    pub initiative_bonus: i32,
+    pub level: i32,
+    pub damage_vulnerabilities: Vec<String>,
+    pub damage_resistances: Vec<String>,
+    pub damage_immunities: Vec<String>,
+    pub save_proficiencies: Vec<String>,
+    pub xp: i32,
+    pub cur_hp: i32,
+    pub temp_hp: i32
 }
 #[derive(Queryable)]
 pub struct Ability {
@@ -82,7 +146,14 @@ pub struct Ability {
     pub uses: i32,
     pub monster_id: Option<i32>,
     pub player_id: Option<i32>,
-    pub item_id: Option<i32>
+    pub item_id: Option<i32>,
+    pub to_hit: Option<String>,
+    pub duration: Option<i32>,
+    pub damage_type: Option<String>,
+    pub healing: bool,
+    pub save_ability: Option<String>,
+    pub save_dc: Option<i32>,
+    pub half_on_save: bool
 }
 #[derive(Insertable, Serialize, Deserialize)]
 #[table_name="abilities"]
@@ -99,7 +170,21 @@ pub struct NewAbility {
     #[serde(default)]
     pub monster_id: Option<i32>,
     #[serde(default)]
-    pub player_id: Option<i32>
+    pub player_id: Option<i32>,
+    #[serde(default)]
+    pub to_hit: Option<String>,
+    #[serde(default)]
+    pub duration: Option<i32>,
+    #[serde(default)]
+    pub damage_type: Option<String>,
+    #[serde(default)]
+    pub healing: bool,
+    #[serde(default)]
+    pub save_ability: Option<String>,
+    #[serde(default)]
+    pub save_dc: Option<i32>,
+    #[serde(default)]
+    pub half_on_save: bool
 }
 #[derive(Queryable)]
 pub struct Room {
@@ -147,7 +232,17 @@ pub struct Combatant {
     pub attack: String,
     pub attack_bonus: i32,
     pub player_id: Option<i32>,
-    pub monster_id: Option<i32>
+    pub monster_id: Option<i32>,
+    pub conditions: Vec<String>,
+    pub death_successes: i32,
+    pub death_failures: i32,
+    pub stable: bool,
+    pub dead: bool,
+    pub temp_hp: i32,
+    /// Dexterity score, for breaking initiative ties.
+    pub init_dex: i32,
+    /// Roll-off result, for breaking ties that dexterity doesn't (0 if there hasn't been one).
+    pub init_tiebreak: i32
 }
 
 #[derive(Insertable)]
@@ -170,8 +265,8 @@ pub struct Property {
 #[derive(Insertable)]
 #[table_name="props"]
 pub struct NewProperty<'a> {
-    name: &'a str,
-    value: &'a str
+    pub name: &'a str,
+    pub value: &'a str
 }
 #[derive(Queryable)]
 pub struct Spell {
@@ -207,12 +302,142 @@ pub struct Buff {
     pub id: i32,
     pub name: String,
     pub descrip: String,
-    pub code: String
+    pub code: String,
+    pub advantage: Vec<String>,
+    pub disadvantage: Vec<String>
 }
 #[derive(Insertable, Serialize, Deserialize)]
 #[table_name="buffs"]
 pub struct NewBuff {
     pub name: String,
     pub descrip: String,
-    pub code: String
+    pub code: String,
+    #[serde(default)]
+    pub advantage: Vec<String>,
+    #[serde(default)]
+    pub disadvantage: Vec<String>
+}
+#[derive(Queryable)]
+pub struct Roll {
+    pub id: i32,
+    pub nick: String,
+    pub room: String,
+    pub player_id: Option<i32>,
+    pub spec: String,
+    pub result: i32,
+    pub breakdown: String,
+    pub seed: i64,
+    pub draw: i64,
+    pub rolled_at: SystemTime,
+    /// What the d20 showed, if this was a d20 roll.
+    pub natural_d20: Option<i32>
+}
+#[derive(Insertable)]
+#[table_name="rolls"]
+pub struct NewRoll<'a> {
+    pub nick: &'a str,
+    pub room: &'a str,
+    pub player_id: Option<i32>,
+    pub spec: &'a str,
+    pub result: i32,
+    pub breakdown: &'a str,
+    pub seed: i64,
+    pub draw: i64,
+    pub natural_d20: Option<i32>
+}
+#[derive(Queryable)]
+pub struct Macro {
+    pub id: i32,
+    pub name: String,
+    pub spec: String,
+    pub player_id: Option<i32>
+}
+#[derive(Insertable)]
+#[table_name="macros"]
+pub struct NewMacro<'a> {
+    pub name: &'a str,
+    pub spec: &'a str,
+    pub player_id: Option<i32>
+}
+#[derive(Queryable)]
+pub struct Effect {
+    pub id: i32,
+    pub name: String,
+    pub kind: String,
+    pub combatant_id: i32,
+    pub source_id: i32,
+    pub rounds_left: i32
+}
+#[derive(Insertable)]
+#[table_name="effects"]
+pub struct NewEffect<'a> {
+    pub name: &'a str,
+    pub kind: &'a str,
+    pub combatant_id: i32,
+    pub source_id: i32,
+    pub rounds_left: i32
+}
+/// How many uses of one of its monster's abilities a combatant has left.
+#[derive(Queryable)]
+pub struct CombatantUse {
+    pub id: i32,
+    pub combatant_id: i32,
+    pub ability_id: i32,
+    pub uses_left: i32
+}
+#[derive(Insertable)]
+#[table_name="combatant_uses"]
+pub struct NewCombatantUse {
+    pub combatant_id: i32,
+    pub ability_id: i32,
+    pub uses_left: i32
+}
+#[derive(Queryable)]
+pub struct EncounterState {
+    pub id: i32,
+    pub cur_combatant: Option<i32>,
+    pub cur_round: i32,
+    pub room_id: Option<i32>,
+    pub started_at: Option<SystemTime>
+}
+#[derive(Insertable)]
+#[table_name="encounter_state"]
+pub struct NewEncounterState {
+    pub cur_combatant: Option<i32>,
+    pub cur_round: i32,
+    pub room_id: Option<i32>,
+    pub started_at: Option<SystemTime>
+}
+#[derive(Queryable)]
+pub struct CombatAction {
+    pub id: i32,
+    pub nick: String,
+    pub command: String,
+    pub logged_at: SystemTime
+}
+#[derive(Insertable)]
+#[table_name="combat_actions"]
+pub struct NewCombatAction<'a> {
+    pub nick: &'a str,
+    pub command: &'a str
+}
+#[derive(Queryable)]
+pub struct CombatChange {
+    pub id: i32,
+    pub action_id: i32,
+    pub kind: String,
+    pub target_id: Option<i32>,
+    pub field: String,
+    pub old_value: String,
+    pub new_value: String
+}
+#[derive(Insertable)]
+#[table_name="combat_changes"]
+pub struct NewCombatChange<'a> {
+    pub action_id: i32,
+    pub kind: &'a str,
+    pub target_id: Option<i32>,
+    pub field: &'a str,
+    pub old_value: &'a str,
+    pub new_value: &'a str
 }
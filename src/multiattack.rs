@@ -0,0 +1,170 @@
+//! Working out which attacks make up a monster's Multiattack, from the SRD's
+//! description of it (e.g. "The dragon makes three attacks: one with its bite
+//! and two with its claws.").
+
+const NUMBERS: &[(&str, usize)] = &[("a", 1), ("one", 1), ("two", 2), ("three", 3), ("four", 4),
+                                    ("five", 5), ("six", 6), ("seven", 7), ("eight", 8)];
+/// Words that can't be part of the name of an attack.
+const STOPWORDS: &[&str] = &[",", ":", "and", "or", "attack", "attacks", "if", "each", "against",
+                             "in", "instead", "then", "uses", "makes", "it"];
+
+fn number(word: &str) -> Option<usize> {
+    NUMBERS.iter().find(|&&(w, _)| w == word).map(|&(_, n)| n)
+        .or_else(|| word.parse().ok())
+}
+/// Lowercases a name, and takes the plural 's' off each word.
+fn normalise(name: &str) -> Vec<String> {
+    name.split(|c: char| !c.is_alphabetic())
+        .filter(|w| w.len() > 0)
+        .map(|w| {
+            let w = w.to_lowercase();
+            if w.len() > 3 && w.ends_with('s') { w[..w.len() - 1].to_string() } else { w }
+        })
+        .collect()
+}
+/// Finds the attack a bit of description (like "claws") refers to.
+fn find_attack<'a>(name: &[&str], attacks: &[(&'a str, bool)]) -> Option<&'a str> {
+    let name = normalise(&name.join(" ")).join(" ");
+    if name == "" {
+        return None;
+    }
+    attacks.iter()
+        .find(|&&(a, _)| {
+            let a = normalise(a).join(" ");
+            a.contains(&name as &str) || name.contains(&a as &str)
+        })
+        .map(|&(a, _)| a)
+}
+/// Reads the name of an attack from the start of `words`, stopping at the first stopword.
+fn take_name<'a, 'b>(words: &'b [&'a str]) -> &'b [&'a str] {
+    let end = words.iter().position(|w| STOPWORDS.contains(w)).unwrap_or(words.len());
+    &words[..end]
+}
+/// Whether an SRD action involves an attack roll (e.g. "Melee Weapon Attack: +0 to hit").
+pub fn is_attack(desc: &str) -> bool {
+    desc.contains("Weapon Attack:") || desc.contains("Spell Attack:")
+}
+/// Lists the attacks a monster makes with its Multiattack, given its description
+/// and the monster's attacks (with whether each one is a melee attack).
+///
+/// Where the description offers alternatives, the first one is used. Returns
+/// nothing if the description can't be made sense of.
+pub fn parse(desc: &str, attacks: &[(&str, bool)]) -> Vec<String> {
+    let sentence = match desc.split('.').find(|s| s.contains("make")) {
+        Some(s) => s.to_lowercase(),
+        None => return vec![]
+    };
+    // only take the first of any alternatives
+    let sentence = sentence.split(" or ").next().unwrap_or("")
+        .replace(",", " , ")
+        .replace(":", " : ")
+        .replace("—", " , ");
+    let words = sentence.split_whitespace().collect::<Vec<_>>();
+    // attacks that are named ("two with its claws"), and ones that aren't ("three melee attacks")
+    let mut named = vec![];
+    let mut generic = None;
+    for (i, w) in words.iter().enumerate() {
+        let n = match number(w) {
+            Some(n) => n,
+            None => continue
+        };
+        let rest = &words[i + 1..];
+        let mut name = take_name(rest);
+        let mut after = &rest[name.len()..];
+        if after.len() > 0 && (after[0] == "attack" || after[0] == "attacks") {
+            after = &after[1..];
+            // "three attacks, either with its longsword"
+            while after.len() > 0 && (after[0] == "," || after[0] == "either") {
+                after = &after[1..];
+            }
+        }
+        else if name.len() > 0 && name[0] != "with" && name[0] != "to" && find_attack(name, attacks).is_none() {
+            // not an attack at all, e.g. "a creature" (but "one bite" is)
+            continue;
+        }
+        // "with its claws", "to constrict"
+        if name.len() == 0 && after.len() >= 2 && after[0] == "with" && after[1] == "its" {
+            name = take_name(&after[2..]);
+        }
+        else if name.len() >= 2 && name[0] == "with" && name[1] == "its" {
+            name = &name[2..];
+        }
+        else if name.len() >= 1 && name[0] == "to" {
+            name = &name[1..];
+        }
+        match name {
+            &[] | &["melee"] | &["ranged"] => {
+                if generic.is_none() {
+                    generic = Some((n, name.get(0).map(|x| *x == "melee")));
+                }
+            },
+            _ => {
+                if let Some(a) = find_attack(name, attacks) {
+                    for _ in 0..n {
+                        named.push(a.to_string());
+                    }
+                }
+            }
+        }
+    }
+    if named.len() > 0 {
+        return named;
+    }
+    if let Some((n, melee)) = generic {
+        let attack = attacks.iter()
+            .find(|&&(_, m)| melee.map(|x| x == m).unwrap_or(true))
+            .map(|&(a, _)| a.to_string());
+        if let Some(a) = attack {
+            return vec![a; n];
+        }
+    }
+    vec![]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DRAGON: &[(&str, bool)] = &[("Bite", true), ("Claw", true), ("Tail", true)];
+
+    #[test]
+    fn counts_named_attacks() {
+        assert_eq!(parse("The dragon can use its Frightful Presence. It then makes three attacks: one with its bite and two with its claws.", DRAGON),
+                   vec!["Bite", "Claw", "Claw"]);
+        assert_eq!(parse("The griffon makes two claw attacks and one bite attack.", DRAGON),
+                   vec!["Claw", "Claw", "Bite"]);
+        assert_eq!(parse("The dragon makes two claw attacks and one bite.", DRAGON),
+                   vec!["Claw", "Claw", "Bite"]);
+        assert_eq!(parse("The behir makes two attacks: one with its bite and one to constrict.",
+                         &[("Bite", true), ("Constrict", true)]),
+                   vec!["Bite", "Constrict"]);
+    }
+    #[test]
+    fn counts_unnamed_attacks() {
+        assert_eq!(parse("The armor makes two melee attacks.", &[("Slam", true)]), vec!["Slam", "Slam"]);
+        assert_eq!(parse("The veteran makes two longsword attacks.", &[("Longsword", true), ("Shortsword", true)]),
+                   vec!["Longsword", "Longsword"]);
+        assert_eq!(parse("The scout makes two ranged attacks.", &[("Shortsword", true), ("Longbow", false)]),
+                   vec!["Longbow", "Longbow"]);
+    }
+    #[test]
+    fn takes_the_first_alternative() {
+        assert_eq!(parse("The centaur makes two attacks: one with its pike and one with its hooves or two with its longbow.",
+                         &[("Pike", true), ("Hooves", true), ("Longbow", false)]),
+                   vec!["Pike", "Hooves"]);
+    }
+    #[test]
+    fn refuses_what_it_cant_read() {
+        assert!(parse("The mummy lord can use its Dreadful Glare.", DRAGON).is_empty());
+        assert!(parse("The hydra makes as many bite attacks as it has heads.", DRAGON).is_empty());
+        assert!(parse("The golem makes two attacks with its fists.", DRAGON).is_empty());
+        assert!(parse("", DRAGON).is_empty());
+        assert!(parse("The dragon makes three attacks.", &[]).is_empty());
+    }
+    #[test]
+    fn knows_attacks_from_their_description() {
+        assert!(is_attack("Melee Weapon Attack: +0 to hit, reach 5 ft., one target. Hit: 1 piercing damage."));
+        assert!(is_attack("Ranged Spell Attack: +7 to hit, range 120 ft., one target."));
+        assert!(!is_attack("The dragon exhales fire in a 60-foot cone."));
+    }
+}
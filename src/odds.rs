@@ -0,0 +1,311 @@
+//! Working out the exact probability distribution of a dicespec.
+use std::collections::BTreeMap;
+use dice::{Attributes, DiceSpec, DiceTerm, Keep, Term, MAX_EXPLOSIONS};
+use errors::*;
+use super::RollMode;
+
+/// A rough limit on how much arithmetic we're prepared to do for one spec.
+pub const MAX_WORK: u64 = 20_000_000;
+/// The most dice we'll work out keep/drop odds for.
+pub const MAX_KEEP_DICE: u32 = 100;
+
+/// A probability distribution over the possible results of a roll.
+#[derive(Clone, Debug)]
+pub struct Distribution {
+    pub probs: BTreeMap<i64, f64>
+}
+impl Distribution {
+    pub fn constant(n: i64) -> Distribution {
+        let mut probs = BTreeMap::new();
+        probs.insert(n, 1.0);
+        Distribution { probs }
+    }
+    /// The distribution of the sum of two independent rolls.
+    pub fn add(&self, other: &Distribution) -> Distribution {
+        let mut probs = BTreeMap::new();
+        for (a, pa) in self.probs.iter() {
+            for (b, pb) in other.probs.iter() {
+                *probs.entry(a + b).or_insert(0.0) += pa * pb;
+            }
+        }
+        Distribution { probs }
+    }
+    /// The distribution of `f` applied to the result.
+    pub fn map<F: Fn(i64) -> i64>(&self, f: F) -> Distribution {
+        let mut probs = BTreeMap::new();
+        for (&x, &p) in self.probs.iter() {
+            *probs.entry(f(x)).or_insert(0.0) += p;
+        }
+        Distribution { probs }
+    }
+    pub fn negate(&self) -> Distribution {
+        Distribution {
+            probs: self.probs.iter().map(|(&x, &p)| (-x, p)).collect()
+        }
+    }
+    /// Mixes several distributions together, each with some probability.
+    fn mix(parts: Vec<(f64, Distribution)>) -> Distribution {
+        let mut probs = BTreeMap::new();
+        for (p, d) in parts {
+            for (x, px) in d.probs {
+                *probs.entry(x).or_insert(0.0) += p * px;
+            }
+        }
+        Distribution { probs }
+    }
+    /// The distribution of rolling twice and keeping the higher (advantage)
+    /// or lower (disadvantage) result.
+    pub fn with_mode(&self, mode: RollMode) -> Distribution {
+        let mut probs = BTreeMap::new();
+        match mode {
+            RollMode::Normal => return self.clone(),
+            RollMode::Advantage => {
+                let mut below = 0.0;
+                for (&x, &p) in self.probs.iter() {
+                    let upto = below + p;
+                    probs.insert(x, upto * upto - below * below);
+                    below = upto;
+                }
+            },
+            RollMode::Disadvantage => {
+                let mut above = 0.0;
+                for (&x, &p) in self.probs.iter().rev() {
+                    let from = above + p;
+                    probs.insert(x, from * from - above * above);
+                    above = from;
+                }
+            }
+        }
+        Distribution { probs }
+    }
+    pub fn min(&self) -> i64 {
+        self.probs.keys().next().cloned().unwrap_or(0)
+    }
+    pub fn max(&self) -> i64 {
+        self.probs.keys().rev().next().cloned().unwrap_or(0)
+    }
+    pub fn mean(&self) -> f64 {
+        self.probs.iter().map(|(&x, &p)| x as f64 * p).sum()
+    }
+    pub fn prob(&self, x: i64) -> f64 {
+        self.probs.get(&x).cloned().unwrap_or(0.0)
+    }
+    pub fn prob_at_least(&self, x: i64) -> f64 {
+        self.probs.range(x..).map(|(_, &p)| p).sum()
+    }
+    /// The chance of a result for which `f` is true.
+    pub fn prob_where<F: Fn(i64) -> bool>(&self, f: F) -> f64 {
+        self.probs.iter().filter(|&(&x, _)| f(x)).map(|(_, &p)| p).sum()
+    }
+}
+fn binomial(n: u32, k: u32) -> f64 {
+    (0..k).fold(1.0, |acc, i| acc * (n - i) as f64 / (i + 1) as f64)
+}
+/// The chance of each face of a single die coming up, taking rerolls into account.
+fn face_probs(t: &DiceTerm) -> Vec<(i64, f64)> {
+    let sides = t.sides as i64;
+    let each = 1.0 / sides as f64;
+    let rerolled = match t.reroll {
+        Some(ref cmp) => (1..sides + 1).filter(|&x| cmp.matches(x)).count() as f64 * each,
+        None => 0.0
+    };
+    (1..sides + 1)
+        .map(|x| {
+            let first = match t.reroll {
+                Some(ref cmp) if cmp.matches(x) => 0.0,
+                _ => each
+            };
+            (x, first + rerolled * each)
+        })
+        .filter(|&(_, p)| p > 0.0)
+        .collect()
+}
+/// What one face contributes to the term: its value, or 1 or 0 if we're counting successes.
+fn contribution(t: &DiceTerm, x: i64) -> i64 {
+    match t.success {
+        Some(ref cmp) => if cmp.matches(x) { 1 } else { 0 },
+        None => x
+    }
+}
+/// The distribution of one die, including any dice it causes to explode.
+fn die_distribution(t: &DiceTerm) -> Distribution {
+    let faces = face_probs(t);
+    let mut ret = Distribution::mix(faces.iter()
+                                    .map(|&(x, p)| (p, Distribution::constant(contribution(t, x))))
+                                    .collect());
+    if let Some(ref cmp) = t.explode {
+        // the roller stops after this many explosions, so we do too
+        for _ in 0..MAX_EXPLOSIONS {
+            ret = Distribution::mix(faces.iter()
+                                    .map(|&(x, p)| {
+                                        let here = Distribution::constant(contribution(t, x));
+                                        if cmp.matches(x) {
+                                            (p, here.add(&ret))
+                                        }
+                                        else {
+                                            (p, here)
+                                        }
+                                    })
+                                    .collect());
+        }
+    }
+    ret
+}
+/// The distribution of a keep/drop term, done by going through the faces in
+/// order of preference and working out how many dice show each one.
+fn keep_distribution(t: &DiceTerm, keep: Keep) -> Distribution {
+    let n = t.count;
+    let (highest, k) = match keep {
+        Keep::Highest(k) => (true, k),
+        Keep::Lowest(k) => (false, k),
+        Keep::DropHighest(d) => (false, n.saturating_sub(d)),
+        Keep::DropLowest(d) => (true, n.saturating_sub(d))
+    };
+    let k = k.min(n);
+    let mut faces = face_probs(t);
+    if highest {
+        faces.reverse();
+    }
+    // state[used] maps the sum of the dice kept so far to its probability
+    let mut state: Vec<BTreeMap<i64, f64>> = vec![BTreeMap::new(); n as usize + 1];
+    state[0].insert(0, 1.0);
+    for &(x, p) in faces.iter() {
+        let mut next: Vec<BTreeMap<i64, f64>> = vec![BTreeMap::new(); n as usize + 1];
+        for used in 0..n + 1 {
+            let kept_before = used.min(k);
+            for (&sum, &prob) in state[used as usize].iter() {
+                for c in 0..(n - used) + 1 {
+                    let w = prob * binomial(n - used, c) * p.powi(c as i32);
+                    if w == 0.0 {
+                        continue;
+                    }
+                    let kept = c.min(k - kept_before) as i64;
+                    let sum = sum + contribution(t, x) * kept;
+                    *next[(used + c) as usize].entry(sum).or_insert(0.0) += w;
+                }
+            }
+        }
+        state = next;
+    }
+    Distribution { probs: state.pop().unwrap() }
+}
+fn term_distribution(t: &DiceTerm) -> Result<Distribution> {
+    let sides = t.sides as u64;
+    let count = t.count as u64;
+    if let Some(keep) = t.keep {
+        if t.explode.is_some() {
+            bail!("I can't work out the odds for exploding dice that are kept or dropped.");
+        }
+        if t.count > MAX_KEEP_DICE || count * count * count * sides * sides > MAX_WORK {
+            bail!("That's too many dice to work out the odds for exactly.");
+        }
+        return Ok(keep_distribution(t, keep));
+    }
+    let depth = if t.explode.is_some() { MAX_EXPLOSIONS as u64 + 1 } else { 1 };
+    if count * count * sides * sides * depth * depth > MAX_WORK {
+        bail!("That's too many dice to work out the odds for exactly.");
+    }
+    let die = die_distribution(t);
+    let mut ret = Distribution::constant(0);
+    for _ in 0..t.count {
+        ret = ret.add(&die);
+    }
+    Ok(ret)
+}
+/// Works out the distribution of a whole dicespec, looking up any `@` references in `attrs`.
+pub fn distribution<A: Attributes>(spec: &DiceSpec, attrs: &A) -> Result<Distribution> {
+    let mut ret = Distribution::constant(0);
+    for p in spec.parts.iter() {
+        let d = match p.term {
+            Term::Dice(ref t) => term_distribution(t)?,
+            Term::Constant(n) => Distribution::constant(n),
+            Term::Reference(ref name) => {
+                let n = attrs.attribute(name)
+                    .ok_or_else(|| ::dice::ReferenceError { name: name.clone() })?;
+                Distribution::constant(n)
+            }
+        };
+        ret = if p.negative { ret.add(&d.negate()) } else { ret.add(&d) };
+    }
+    Ok(ret)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use dice::{parse, NoAttributes};
+
+    fn odds(spec: &str) -> Distribution {
+        distribution(&parse(spec).unwrap(), &NoAttributes).unwrap()
+    }
+    fn close(a: f64, b: f64) -> bool {
+        (a - b).abs() < 1e-9
+    }
+
+    #[test]
+    fn sums_dice() {
+        let d = odds("2d6");
+        assert_eq!((d.min(), d.max()), (2, 12));
+        assert!(close(d.mean(), 7.0));
+        assert!(close(d.prob(7), 6.0 / 36.0));
+        assert!(close(d.prob(2), 1.0 / 36.0));
+        let d = odds("1d4-1");
+        assert_eq!((d.min(), d.max()), (0, 3));
+        assert!(close(d.mean(), 1.5));
+    }
+    #[test]
+    fn keeps_highest() {
+        let d = odds("2d20kh1");
+        assert!(close(d.prob(20), 39.0 / 400.0));
+        assert!(close(d.prob(1), 1.0 / 400.0));
+        assert!(close(d.mean(), 13.825));
+        let adv = odds("1d20").with_mode(RollMode::Advantage);
+        for x in 1..21 {
+            assert!(close(d.prob(x), adv.prob(x)));
+        }
+        let dis = odds("1d20").with_mode(RollMode::Disadvantage);
+        assert!(close(dis.mean(), 21.0 - 13.825));
+    }
+    #[test]
+    fn keeps_three_of_four() {
+        let d = odds("4d6kh3");
+        assert_eq!((d.min(), d.max()), (3, 18));
+        assert!(close(d.prob(18), 21.0 / 1296.0));
+        assert!(close(d.mean(), 15869.0 / 1296.0));
+        let same = odds("4d6dl1");
+        assert!(close(d.mean(), same.mean()));
+    }
+    #[test]
+    fn counts_successes() {
+        let d = odds("4d6>=5");
+        assert_eq!((d.min(), d.max()), (0, 4));
+        assert!(close(d.prob(0), 16.0 / 81.0));
+        assert!(close(d.prob(4), 1.0 / 81.0));
+        assert!(close(d.mean(), 4.0 / 3.0));
+    }
+    #[test]
+    fn rerolls() {
+        let d = odds("1d6r1");
+        assert!(close(d.prob(1), 1.0 / 36.0));
+        assert!(close(d.prob(2), 7.0 / 36.0));
+    }
+    #[test]
+    fn explodes() {
+        let d = odds("1d6!");
+        assert!(close(d.probs.values().sum(), 1.0));
+        // a 6 always explodes into something more
+        assert_eq!(d.prob(6), 0.0);
+        assert!(close(d.prob(7), 1.0 / 36.0));
+        assert!(close(d.mean(), 4.2));
+        assert_eq!(d.max(), 6 * (MAX_EXPLOSIONS as i64 + 1));
+    }
+    #[test]
+    fn refuses_what_it_cant_do() {
+        let spec = parse("4d6!kh3").unwrap();
+        assert!(distribution(&spec, &NoAttributes).is_err());
+        let spec = parse("1000d1000").unwrap();
+        assert!(distribution(&spec, &NoAttributes).is_err());
+        let spec = parse("1d20+@str").unwrap();
+        assert!(distribution(&spec, &NoAttributes).is_err());
+    }
+}
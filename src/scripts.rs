@@ -4,8 +4,10 @@ use diesel::pg::PgConnection;
 use std::rc::Rc;
 use std::cell::RefCell;
 use schema::players::dsl as pdsl;
+use schema::combatants::dsl as cdsl;
 use std::fmt;
 use gm::MatrixClient;
+use DiceTray;
 
 pub struct Chat {
     pub inner: Rc<RefCell<MatrixClient>>
@@ -61,22 +63,67 @@ macro_rules! ketos_fns {
         }
     }
 }
+fn send(mx: &RefCell<MatrixClient>, to: &str, msg: &str) -> Result<(), ::ketos::Error> {
+    use gm::types::*;
+    use std::error::Error as StdError;
+    let m = Message::Notice { body: msg.into(), formatted_body: Some(msg.replace("\n", "<br/>")), format: Some("org.matrix.custom.html".into()) };
+    mx.borrow_mut().send(to, m).map_err(|e| Box::new(e) as Box<StdError>)?;
+    ::std::thread::sleep(::std::time::Duration::from_millis(250));
+    Ok(())
+}
 pub fn register_matrix(scope: &Scope, mx: Rc<RefCell<MatrixClient>>) {
     use ketos::Error as KetosError;
     use ketos::Value;
-    use std::error::Error as StdError;
     scope.add_named_value("mx", Value::Foreign(Rc::new(Chat {
         inner: mx
     })));
     fn print(ch: &Chat, to: &str, msg: &str) -> Result<(), KetosError> {
-        use gm::types::*;
-        let m = Message::Notice { body: msg.into(), formatted_body: Some(msg.replace("\n", "<br/>")), format: Some("org.matrix.custom.html".into()) };
-        ch.inner.borrow_mut().send(to, m).map_err(|e| Box::new(e) as Box<StdError>)?;
-        ::std::thread::sleep(::std::time::Duration::from_millis(250));
-        Ok(())
+        send(&ch.inner, to, msg)
     }
     ketos_fn! { scope => "msg" => fn print(ch: &Chat, to: &str, msg: &str) -> () }
 }
+pub fn register_dice(scope: &Scope, tray: DiceTray) {
+    use ketos::Error as KetosError;
+    use std::error::Error as StdError;
+    use dice::NoAttributes;
+    use Roller;
+    // this closes over the dice, so scripts can keep on calling (roll "2d6")
+    let roll = move |spec: &str| -> Result<i64, KetosError> {
+        let roll = tray.roll(spec, &NoAttributes, Roller::Nobody)
+            .map_err(|e| Box::new(e) as Box<StdError>)?;
+        Ok(roll.total)
+    };
+    ketos_fn! { scope => "roll" => fn roll(spec: &str) -> i64 }
+}
+pub fn register_saves(scope: &Scope, mx: Rc<RefCell<MatrixClient>>, tray: DiceTray) {
+    use ketos::Error as KetosError;
+    use std::error::Error as StdError;
+    use diesel::prelude::*;
+    use {SavingThrow, RollMode, Roller};
+    // the save is described in the room the command came from, like any other,
+    // and the description is handed back too (for scripts run from elsewhere)
+    let save = move |db: &Database, id: i32, ability: &str, dc: i32| -> Result<(bool, String), KetosError> {
+        let (save, comb) = {
+            let db = db.inner.borrow();
+            let comb = cdsl::combatants.filter(cdsl::id.eq(id))
+                .get_result::<Combatant>(&*db).map_err(|e| Box::new(e) as Box<StdError>)?;
+            let save = SavingThrow::prepare(&*db, &comb, ability, RollMode::Normal)
+                .map_err(|e| Box::new(e) as Box<StdError>)?;
+            (save, comb)
+        };
+        let d20 = match save.auto_fail {
+            Some(_) => None,
+            None => Some(tray.roll_d20(save.mode, Roller::of(&comb)).map_err(|e| Box::new(e) as Box<StdError>)?)
+        };
+        let (passed, desc) = save.resolve(&comb.name, d20.as_ref(), dc as i64);
+        let room = tray.context.borrow().clone().map(|(_, room)| room);
+        if let Some(room) = room {
+            send(&mx, &room, &desc)?;
+        }
+        Ok((passed, desc))
+    };
+    ketos_fn! { scope => "save" => fn save(db: &Database, id: i32, ability: &str, dc: i32) -> (bool, String) }
+}
 ketos_fns!(register_players, pdsl, players, Player |
            name, String, &str,
            typ, String, &str,
@@ -88,7 +135,11 @@ ketos_fns!(register_players, pdsl, players, Player |
            constitution, i32, i32,
            wisdom, i32, i32,
            charisma, i32, i32,
-           initiative_bonus, i32, i32
+           initiative_bonus, i32, i32,
+           level, i32, i32,
+           xp, i32, i32,
+           cur_hp, i32, i32,
+           temp_hp, i32, i32
 );
 impl ForeignValue for Player {
     fn type_name(&self) -> &'static str { "Player" }